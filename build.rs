@@ -0,0 +1,7 @@
+//! The migrations are embedded by diesel_migrations, which only sees the files it
+//! read : rebuild when a migration is added
+
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+    println!("cargo:rerun-if-changed=migrations_sqlite");
+}
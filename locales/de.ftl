@@ -0,0 +1,41 @@
+# Mails
+register-subject = Willkommen bei { $platform } !
+register-header = Willkommen, { $username }
+register-text = Sie sind jetzt bei { $platform } registriert!
+
+reset-subject = { $platform } : Passwort zurücksetzen
+reset-text = Wir haben eine Anfrage erhalten, das Passwort des Kontos { $username } zurückzusetzen, das mit { $email } verknüpft ist.
+reset-warning = Falls Sie kein neues Passwort angefordert haben, teilen Sie uns dies bitte umgehend mit, indem Sie auf diese E-Mail antworten.
+reset-instructions = Sie können Ihr Passwort über den folgenden Link zurücksetzen :
+reset-button = Passwort zurücksetzen
+
+password-changed-subject = { $platform } : Passwort geändert
+password-changed-text = Das Passwort des Kontos { $username }, das mit { $email } verknüpft ist, wurde geändert.
+
+mail-footer = Sie erhalten diese E-Mail, weil Sie ein Konto bei { $platform } haben.
+
+# Dashboard
+dashboard-title = { $platform } Dashboard
+dashboard-email = E-Mail :
+dashboard-password = Passwort :
+dashboard-submit = Senden
+dashboard-login-failed = Ungültige E-Mail oder ungültiges Passwort
+dashboard-users = Benutzer
+dashboard-search = Suchen
+dashboard-username = Benutzername :
+dashboard-role = Rolle :
+dashboard-status = Status :
+dashboard-locale = Sprache :
+dashboard-created = Registriert :
+dashboard-admin = Administrator
+dashboard-user = Benutzer
+dashboard-active = Aktiv
+dashboard-inactive = Deaktiviert
+dashboard-back = Zurück
+dashboard-edit = Bearbeiten
+dashboard-save = Speichern
+dashboard-promote = Zum Administrator machen
+dashboard-demote = Zum Benutzer herabstufen
+dashboard-activate = Aktivieren
+dashboard-deactivate = Deaktivieren
+dashboard-impersonate = Als dieser Benutzer anmelden
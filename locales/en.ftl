@@ -0,0 +1,41 @@
+# Mails
+register-subject = Welcome on { $platform } !
+register-header = Welcome, { $username }
+register-text = You are now register on { $platform } !
+
+reset-subject = { $platform } : Password Reset
+reset-text = We have received a request to reset the password for the { $username } account associated with { $email }.
+reset-warning = If you did not request new password, please let us know immediately by replying to this email.
+reset-instructions = You can reset your password by clicking the link below :
+reset-button = Reset your password
+
+password-changed-subject = { $platform } : Password Reset Success
+password-changed-text = The password for the { $username } account associated with { $email } has changed.
+
+mail-footer = You receive this email because you have an account on { $platform }.
+
+# Dashboard
+dashboard-title = { $platform } Dashboard
+dashboard-email = E-mail :
+dashboard-password = Password :
+dashboard-submit = Send
+dashboard-login-failed = Invalid email or password
+dashboard-users = Users
+dashboard-search = Search
+dashboard-username = Username :
+dashboard-role = Role :
+dashboard-status = Status :
+dashboard-locale = Language :
+dashboard-created = Registered :
+dashboard-admin = Administrator
+dashboard-user = User
+dashboard-active = Active
+dashboard-inactive = Deactivated
+dashboard-back = Back
+dashboard-edit = Edit
+dashboard-save = Save
+dashboard-promote = Promote to administrator
+dashboard-demote = Demote to user
+dashboard-activate = Activate
+dashboard-deactivate = Deactivate
+dashboard-impersonate = Impersonate
@@ -0,0 +1,41 @@
+# Mails
+register-subject = Bienvenue sur { $platform } !
+register-header = Bienvenue, { $username }
+register-text = Vous êtes maintenant inscrit sur { $platform } !
+
+reset-subject = { $platform } : Réinitialisation du mot de passe
+reset-text = Nous avons reçu une demande de réinitialisation du mot de passe du compte { $username } associé à { $email }.
+reset-warning = Si vous n'êtes pas à l'origine de cette demande, merci de nous prévenir immédiatement en répondant à ce mail.
+reset-instructions = Vous pouvez réinitialiser votre mot de passe en cliquant sur le lien ci-dessous :
+reset-button = Réinitialiser votre mot de passe
+
+password-changed-subject = { $platform } : Mot de passe modifié
+password-changed-text = Le mot de passe du compte { $username } associé à { $email } a été modifié.
+
+mail-footer = Vous recevez ce mail car vous avez un compte sur { $platform }.
+
+# Dashboard
+dashboard-title = Tableau de bord { $platform }
+dashboard-email = E-mail :
+dashboard-password = Mot de passe :
+dashboard-submit = Envoyer
+dashboard-login-failed = E-mail ou mot de passe invalide
+dashboard-users = Utilisateurs
+dashboard-search = Rechercher
+dashboard-username = Nom d'utilisateur :
+dashboard-role = Rôle :
+dashboard-status = Statut :
+dashboard-locale = Langue :
+dashboard-created = Inscription :
+dashboard-admin = Administrateur
+dashboard-user = Utilisateur
+dashboard-active = Actif
+dashboard-inactive = Désactivé
+dashboard-back = Retour
+dashboard-edit = Modifier
+dashboard-save = Enregistrer
+dashboard-promote = Promouvoir administrateur
+dashboard-demote = Rétrograder en utilisateur
+dashboard-activate = Activer
+dashboard-deactivate = Désactiver
+dashboard-impersonate = Se connecter en tant que
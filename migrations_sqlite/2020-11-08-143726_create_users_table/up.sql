@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE users (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    is_admin BOOLEAN NOT NULL DEFAULT 0,
+    username VARCHAR NOT NULL,
+    email VARCHAR(100) NOT NULL UNIQUE,
+    token_key TEXT NOT NULL,
+    password_hash TEXT NOT NULL,
+    reset_token TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
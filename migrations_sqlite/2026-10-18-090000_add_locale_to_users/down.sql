@@ -0,0 +1,19 @@
+-- This file should undo anything in `up.sql`
+-- SQLite can't drop a column before 3.35, the table is copied without it
+CREATE TABLE users_without_locale (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    is_admin BOOLEAN NOT NULL DEFAULT 0,
+    username VARCHAR NOT NULL,
+    email VARCHAR(100) NOT NULL UNIQUE,
+    token_key TEXT NOT NULL,
+    password_hash TEXT NOT NULL,
+    reset_token TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+INSERT INTO users_without_locale
+    SELECT id, is_admin, username, email, token_key, password_hash, reset_token,
+        created_at, updated_at
+    FROM users;
+DROP TABLE users;
+ALTER TABLE users_without_locale RENAME TO users;
@@ -0,0 +1,151 @@
+//! The application : its state, routes and middlewares, shared by the server and the tests
+
+use actix::Addr;
+use actix_files::Files;
+use actix_service::ServiceFactory;
+use actix_web::body::Body;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{web, App, Error};
+use std::sync::Arc;
+
+use crate::db::cache::SessionKeys;
+use crate::db::repository::{self, Diesel};
+use crate::db::{DbPool, ReadPool};
+use crate::handlers;
+use crate::i18n::Locales;
+use crate::mails::Postman;
+use crate::middlewares;
+use crate::middlewares::allowlist::IpAllowlist;
+use crate::middlewares::session::{BrancaSession, Level, SessionCookie};
+use crate::settings::Settings;
+use crate::templates::Templates;
+use crate::urls::UrlBuilder;
+
+/// What the handlers get in the app data, built once at startup and cloned for every worker
+#[derive(Clone)]
+pub struct AppState {
+    pub settings: Settings,
+    pub pool: DbPool,
+    pub read_pool: ReadPool,
+    pub repository: Arc<Diesel>,
+    pub session_keys: web::Data<SessionKeys>,
+    pub postman: Addr<Postman>,
+    pub templates: Templates,
+    pub locales: Locales,
+    pub urls: UrlBuilder,
+    pub session_cookie: SessionCookie,
+    pub health_allowlist: IpAllowlist,
+    pub metrics_allowlist: IpAllowlist,
+}
+
+/// Every route of the api, behind its middlewares
+pub fn app(
+    state: &AppState,
+) -> App<
+    impl ServiceFactory<
+        Config = (),
+        Request = ServiceRequest,
+        Response = ServiceResponse<Body>,
+        Error = Error,
+        InitError = (),
+    >,
+    Body,
+> {
+    App::new()
+        // add the pool to app state
+        .data(state.pool.clone())
+        .data(state.read_pool.clone())
+        .configure(repository::configure(state.repository.clone()))
+        .app_data(state.session_keys.clone())
+        .data(state.postman.clone())
+        .data(state.templates.clone())
+        .data(state.locales.clone())
+        .data(state.urls.clone())
+        .data(state.session_cookie.clone())
+        .data(state.settings.clone())
+        // PURE API
+        .service(
+            web::scope("/api/v1")
+                // lock down routes with User Middleware
+                .wrap(BrancaSession(Level::User))
+                // AUTH routes
+                .route("/login", web::post().to(handlers::user::login))
+                .route("/logout", web::get().to(handlers::user::logout))
+                .route("/refresh", web::post().to(handlers::user::refresh))
+                // USER routes
+                .service(
+                    web::scope("/user")
+                        .route("", web::get().to(handlers::user::get))
+                        .route("/update", web::put().to(handlers::user::update))
+                        .route("/delete", web::delete().to(handlers::user::delete))
+                        .route("/register", web::post().to(handlers::user::register))
+                        .route(
+                            "/forgot_password",
+                            web::post().to(handlers::user::forgot_password),
+                        )
+                        .route(
+                            "/reset_password",
+                            web::post().to(handlers::user::reset_password),
+                        )
+                        .route(
+                            "/change_password",
+                            web::post().to(handlers::user::change_password),
+                        ),
+                ),
+        )
+        // DASHBOARD
+        .service(
+            web::scope("/dashboard/")
+                // lock down routes with Admin Middleware
+                .wrap(BrancaSession(Level::Admin))
+                .route("login", web::get().to(handlers::dashboard::dashboard_login))
+                .route(
+                    "login",
+                    web::post().to(handlers::dashboard::dashboard_login_post),
+                )
+                .route("users", web::get().to(handlers::dashboard::users))
+                .route("users/{id}", web::get().to(handlers::dashboard::user))
+                .route(
+                    "users/{id}/edit",
+                    web::get().to(handlers::dashboard::edit_user),
+                )
+                .route(
+                    "users/{id}/edit",
+                    web::post().to(handlers::dashboard::update_user),
+                )
+                .route(
+                    "users/{id}/{action}",
+                    web::post().to(handlers::dashboard::user_action),
+                )
+                .route("mails", web::get().to(handlers::dashboard::mails))
+                .route(
+                    "mails/{kind}/preview",
+                    web::get().to(handlers::dashboard::preview_mail),
+                )
+                .route(
+                    "mails/{kind}/send",
+                    web::post().to(handlers::dashboard::send_test_mail),
+                )
+                .service(Files::new("", &state.settings.dashboard_path).index_file("index.html")),
+        )
+        // PROBES, for the orchestrator
+        .service(
+            web::scope("/health")
+                .wrap(state.health_allowlist.clone())
+                .route("/live", web::get().to(handlers::health::live))
+                .route("/ready", web::get().to(handlers::health::ready)),
+        )
+        .service(
+            web::scope("/metrics")
+                .wrap(state.metrics_allowlist.clone())
+                .route("", web::get().to(handlers::metrics::metrics)),
+        )
+        // PUBLICS FILES
+        .service(Files::new("/", &state.settings.public_path))
+        // CSRF protection for cookie-authenticated requests
+        .wrap(middlewares::csrf::Csrf)
+        // count and time every request, including the refused ones
+        .wrap(middlewares::metrics::RequestMetrics)
+        // request id and span, logging every request - always register it last
+        .wrap(middlewares::request_id::RequestId)
+}
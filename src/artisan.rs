@@ -1,4 +1,6 @@
- 
+// artisan shares the modules of the api, but only uses a part of them
+#![allow(dead_code)]
+
 #[macro_use]
 extern crate diesel;
 extern crate dotenv;
@@ -10,119 +12,252 @@ extern crate serde_json;
 extern crate chrono;
 
 use dotenv::dotenv;
+use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
 
-mod middlewares;
-mod handlers;
 mod db;
-mod templates;
-mod mails;
 mod errors;
+mod i18n;
+mod logging;
+mod mails;
+mod metrics;
+mod middlewares;
+mod server;
+mod settings;
+mod telemetry;
+mod templates;
+mod urls;
 
-use crate::{
-  db as database,
-  errors::*,
-  mails as mail
-};
+use crate::db::{cache::SessionKeys, repository::UserRepository};
+use crate::{db as database, errors::*, mails as mail, urls::Flow};
 
 #[derive(StructOpt, Debug)]
 #[structopt(about = "a tool to manage db and api")]
 enum Cli {
-
     CreateUser {
-      #[structopt(short, help = "The new user will be admin")]
-      admin: bool,
-      #[structopt(short, long)]
-      email: String,
-      #[structopt(short, long)]
-      password: String,
-      #[structopt(short, long)]
-      username: Option<String>,
+        #[structopt(short, help = "The new user will be admin")]
+        admin: bool,
+        #[structopt(short, long)]
+        email: String,
+        #[structopt(short, long)]
+        password: String,
+        #[structopt(short, long)]
+        username: Option<String>,
     },
 
     DeleteUser {
-      email: String,
+        email: String,
     },
 
     SendMail {
-      to: String,
-      title: String,
-      content: String,
+        to: String,
+        title: String,
+        content: String,
     },
 
+    #[structopt(about = "Send the welcome mail, in the locale of the user")]
     SendRegisterMail {
-      to: String,
-      username: String,
+        to: String,
     },
 
+    #[structopt(about = "Send a reset password link holding `token`, in the locale of the user")]
     SendResetMail {
-      to: String,
-      username: String,
-      token: String,
+        to: String,
+        token: String,
     },
 
-}
-
-fn main() -> Result<(), ApiError> {
-  let args = Cli::from_args();
-  dotenv().ok();
-  env_logger::init();
-
-  let pool = database::init_pool().expect("Failed to create pool");
-
-  match args {
-
-    // ./artisan create-user -a -e florian.zebidi@gmx.fr -p eindoven -u Florian
-    Cli::CreateUser {admin, email, password, username} => {
-
-      let conn = pool.get() ? ;
-
-      let name = match username {
-        Some(x) => x,
-        None    => "admin".to_string(),
-      };
-
-      let result = database::user::register(
-        admin,
-        name.as_ref(),
-        password.as_ref(),
-        email.as_ref(),
-        &conn
-      ) ? ;
-
-      println!("Succefully created new user, id : {}", result);
-      Ok(())
-    },
+    #[structopt(about = "Print the loaded settings, secrets redacted")]
+    PrintSettings,
 
+    #[structopt(about = "Manage the migrations embedded in the binary")]
+    Migration(MigrationCommand),
+}
 
-    Cli::DeleteUser {email} => {
-      println!("delete");
-      Ok(())
-    },
-
-
-    // ./artisan send-mail "e.k.florian@gmail.com" "<h1>Je t'ai écris un mail en HTML avec du Rust</h1>Accessoirement c'est trop bien."
-    Cli::SendMail {to, title, content} => {
-
-      mail::send_mail(&to, &title, &content) ? ;
-      Ok(())
-
-    },
-
-    Cli::SendRegisterMail {to, username} => {
-
-      mail::user::send_register_email(&to, &username) ? ;
-      Ok(())
-
+#[derive(StructOpt, Debug)]
+enum MigrationCommand {
+    #[structopt(about = "List every migration and whether it ran")]
+    List,
+    #[structopt(about = "Run the pending migrations")]
+    Run,
+    #[structopt(
+        about = "Revert the latest migration, with the down.sql of the migrations directory"
+    )]
+    Revert {
+        #[structopt(long, parse(from_os_str), help = "The migrations directory")]
+        dir: Option<PathBuf>,
     },
-
-    Cli::SendResetMail {to, username, token} => {
-
-      mail::user::send_reset_token_email(&to, &username, &token) ? ;
-      Ok(())
-
+    #[structopt(
+        about = "Revert and run again the latest migration, with the SQL of the migrations directory"
+    )]
+    Redo {
+        #[structopt(long, parse(from_os_str), help = "The migrations directory")]
+        dir: Option<PathBuf>,
     },
+    #[structopt(about = "Tell whether the schema is up to date")]
+    Status,
+}
 
+fn main() -> Result<(), ApiError> {
+    let args = Cli::from_args();
+    dotenv().ok();
+    env_logger::init();
+
+    let settings = settings::load()?;
+    let pool = database::from_settings(&settings).expect("Failed to connect to the database");
+    // like the api's, without session cache : artisan serves no session
+    let users = database::repository::Diesel::new(
+        pool.clone(),
+        database::ReadPool(pool),
+        actix_web::web::Data::new(SessionKeys::new(0, Duration::from_secs(0))),
+    );
+
+    match args {
+        // ./artisan create-user -a -e florian.zebidi@gmx.fr -p eindoven -u Florian
+        Cli::CreateUser {
+            admin,
+            email,
+            password,
+            username,
+        } => {
+            let name = match username {
+                Some(x) => x,
+                None => "admin".to_string(),
+            };
+
+            let result = users.register(
+                admin,
+                name.as_ref(),
+                password.as_ref(),
+                email.as_ref(),
+                "en",
+            )?;
+
+            println!("Succefully created new user, id : {}", result);
+            Ok(())
+        }
+
+        Cli::DeleteUser { email } => {
+            let user = users.get_by_email(&email)?;
+            users.delete(user.id)?;
+
+            println!("Succefully deleted user, id : {}", user.id);
+            Ok(())
+        }
+
+        // ./artisan send-mail "e.k.florian@gmail.com" "Hello" "<h1>Je t'ai écris un mail en HTML avec du Rust</h1>Accessoirement c'est trop bien."
+        Cli::SendMail { to, title, content } => send(
+            &settings,
+            mail::SendableEmail {
+                to,
+                title,
+                content,
+                ..Default::default()
+            },
+        ),
+
+        Cli::SendRegisterMail { to } => {
+            let user = users.get_by_email(&to)?;
+            let templates = templates::from_settings(&settings)?;
+            let locales = i18n::from_settings(&settings)?;
+
+            send(
+                &settings,
+                mail::user::create_register_email(
+                    &templates,
+                    &locales,
+                    &user.locale,
+                    &to,
+                    &user.username,
+                )?,
+            )
+        }
+
+        Cli::SendResetMail { to, token } => {
+            let user = users.get_by_email(&to)?;
+            let templates = templates::from_settings(&settings)?;
+            let locales = i18n::from_settings(&settings)?;
+            let url = urls::from_settings(&settings).build(
+                Flow::ResetPassword,
+                &[("locale", &user.locale)],
+                &[("user", &user.id.to_string()), ("token", &token)],
+            )?;
+
+            send(
+                &settings,
+                mail::user::create_reset_token_email(
+                    &templates,
+                    &locales,
+                    &user.locale,
+                    &to,
+                    &user.username,
+                    &url,
+                )?,
+            )
+        }
+
+        // ./artisan print-settings > settings.toml
+        Cli::PrintSettings => {
+            print!("{}", settings);
+            Ok(())
+        }
+
+        // ./artisan migration run
+        Cli::Migration(command) => {
+            let conn = database::migrations::connect(&settings)?;
+            let directory = |dir: Option<PathBuf>| {
+                dir.unwrap_or_else(|| PathBuf::from(database::migrations::DIRECTORY))
+            };
+
+            match command {
+                MigrationCommand::List => {
+                    for (version, done) in database::migrations::status(&conn)? {
+                        println!("[{}] {}", if done { "x" } else { " " }, version);
+                    }
+                }
+                MigrationCommand::Run => {
+                    for version in database::migrations::run_pending(&conn)? {
+                        println!("Ran {}", version);
+                    }
+                }
+                MigrationCommand::Revert { dir } => {
+                    match database::migrations::revert_last(&conn, &directory(dir))? {
+                        Some(version) => println!("Reverted {}", version),
+                        None => println!("No migration to revert"),
+                    }
+                }
+                MigrationCommand::Redo { dir } => {
+                    match database::migrations::redo(&conn, &directory(dir))? {
+                        Some(version) => println!("Redid {}", version),
+                        None => println!("No migration to redo"),
+                    }
+                }
+                MigrationCommand::Status => {
+                    let pending = database::migrations::pending(&conn)?;
+                    match pending.len() {
+                        0 => println!("The schema is up to date"),
+                        n => println!(
+                            "{} pending migrations, the latest being {}",
+                            n,
+                            pending[n - 1]
+                        ),
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+}
 
-  }
-}
\ No newline at end of file
+/// Send a mail right away, through the transport and DKIM key of the settings
+fn send(settings: &settings::Settings, email: mail::SendableEmail) -> Result<(), ApiError> {
+    let to = email.to.clone();
+    mail::send_mail(
+        email,
+        &mail::Sender::from_settings(settings)?,
+        mail::transport::from_settings(settings)?.as_mut(),
+        mail::dkim::from_settings(settings)?.as_ref(),
+    )?;
+    println!("Mail sent to {}", to);
+    Ok(())
+}
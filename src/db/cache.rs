@@ -0,0 +1,164 @@
+use actix_web::web;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::metrics::SESSION_CACHE;
+use crate::settings::Settings;
+
+/// What verifying a session token needs from the user row
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionKey {
+    pub token_key: String,
+    pub is_admin: bool,
+    pub is_active: bool,
+}
+
+/// Session keys by user id, so the session middleware doesn't query the
+/// database on every request. Entries expire after a short time, and are
+/// dropped as soon as the api updates the user. Shared by the workers through
+/// the app data, the middleware doesn't cache without it.
+///
+/// A key read from the database is only cached when no user was invalidated
+/// since the read started, see [`SessionKeys::generation`].
+pub struct SessionKeys {
+    entries: Mutex<BTreeMap<i32, (Instant, SessionKey)>>,
+    /// bumped by every invalidation, under the lock of `entries`
+    generation: AtomicU64,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl SessionKeys {
+    /// Keep up to `capacity` keys for `ttl`, 0 disables the cache
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        SessionKeys {
+            entries: Mutex::new(BTreeMap::new()),
+            generation: AtomicU64::new(0),
+            capacity,
+            ttl,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<i32, (Instant, SessionKey)>> {
+        // a poisoned cache only holds copies of the database, still usable
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn enabled(&self) -> bool {
+        self.capacity > 0 && self.ttl > Duration::from_secs(0)
+    }
+
+    pub fn get(&self, user: i32) -> Option<SessionKey> {
+        if !self.enabled() {
+            return None;
+        }
+        let mut entries = self.lock();
+        let key = match entries.get(&user) {
+            Some((expires, key)) if *expires > Instant::now() => Some(key.clone()),
+            Some(_) => {
+                entries.remove(&user);
+                None
+            }
+            None => None,
+        };
+        let result = if key.is_some() { "hit" } else { "miss" };
+        SESSION_CACHE.inc(&[("result", result)]);
+        key
+    }
+
+    /// To read before fetching a key from the database, then to give to [`SessionKeys::insert`]
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Cache the key read since `generation`, unless a user was invalidated meanwhile :
+    /// the key may be the one the invalidation revoked
+    pub fn insert(&self, user: i32, key: SessionKey, generation: u64) {
+        if !self.enabled() {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.lock();
+        if self.generation.load(Ordering::SeqCst) != generation {
+            return;
+        }
+        if !entries.contains_key(&user) && entries.len() >= self.capacity {
+            entries.retain(|_, (expires, _)| *expires > now);
+        }
+        // still full, the key closest to expiring leaves
+        if !entries.contains_key(&user) && entries.len() >= self.capacity {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (expires, _))| *expires)
+                .map(|(user, _)| *user);
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(user, (now + self.ttl, key));
+    }
+
+    /// Forget the key of a user whose password, role, state or account changed
+    pub fn invalidate(&self, user: i32) {
+        let mut entries = self.lock();
+        entries.remove(&user);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// The cache sized by `session_cache_capacity` and `session_cache_ttl`
+pub fn from_settings(settings: &Settings) -> web::Data<SessionKeys> {
+    web::Data::new(SessionKeys::new(
+        settings.session_cache_capacity,
+        Duration::from_secs(settings.session_cache_ttl),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(admin: bool) -> SessionKey {
+        SessionKey {
+            token_key: "k".repeat(32),
+            is_admin: admin,
+            is_active: true,
+        }
+    }
+
+    #[test]
+    fn keys_expire_are_bounded_and_invalidated() {
+        let disabled = SessionKeys::new(0, Duration::from_secs(60));
+        disabled.insert(1, key(false), disabled.generation());
+        assert_eq!(disabled.get(1), None, "disabled without capacity");
+
+        let cache = SessionKeys::new(2, Duration::from_millis(50));
+        cache.insert(1, key(false), cache.generation());
+        cache.insert(2, key(true), cache.generation());
+        cache.insert(3, key(false), cache.generation());
+        assert_eq!(cache.get(1), None, "the oldest key is evicted");
+        assert_eq!(cache.get(2), Some(key(true)));
+
+        cache.invalidate(2);
+        assert_eq!(cache.get(2), None);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get(3), None, "expired");
+    }
+
+    #[test]
+    fn keys_read_before_an_invalidation_are_not_cached() {
+        let cache = SessionKeys::new(10, Duration::from_secs(60));
+        // the middleware misses and reads the key from the database...
+        let generation = cache.generation();
+        // ...while the password changes
+        cache.invalidate(1);
+        cache.insert(1, key(true), generation);
+        assert_eq!(cache.get(1), None, "the revoked key is not cached");
+
+        cache.insert(1, key(false), cache.generation());
+        assert_eq!(cache.get(1), Some(key(false)));
+    }
+}
@@ -0,0 +1,229 @@
+use bcrypt::{hash, verify};
+use chrono::Utc;
+use rand::{distributions::Alphanumeric, Rng};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use super::cache::SessionKey;
+use super::models::User;
+use super::repository::{SessionRepository, TokenRepository, UserRepository};
+use super::user;
+use crate::errors::ApiError;
+use diesel::result::Error as DieselError;
+
+/// bcrypt's cheapest cost, the tests hash a lot
+const COST: u32 = 4;
+
+/// The repositories in a map, for the tests of the handlers.
+/// Fails like the diesel ones, e.g. with diesel's `NotFound` for a missing user.
+#[derive(Default)]
+pub struct Memory {
+    users: Mutex<BTreeMap<i32, User>>,
+    last_id: AtomicI32,
+    /// reset tokens by email
+    reset_tokens: Mutex<BTreeMap<String, String>>,
+}
+
+fn random(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .collect()
+}
+
+fn not_found() -> ApiError {
+    DieselError::NotFound.into()
+}
+
+impl Memory {
+    fn users(&self) -> MutexGuard<'_, BTreeMap<i32, User>> {
+        self.users.lock().unwrap()
+    }
+
+    fn with_email<T>(&self, email: &str, work: impl FnOnce(&mut User) -> T) -> Result<T, ApiError> {
+        let mut users = self.users();
+        let user = users
+            .values_mut()
+            .find(|user| user.email == email)
+            .ok_or_else(not_found)?;
+        Ok(work(user))
+    }
+
+    fn cloned(&self, id: i32) -> Result<User, ApiError> {
+        self.users().get(&id).cloned().ok_or_else(not_found)
+    }
+
+    fn with_id(&self, id: i32, work: impl FnOnce(&mut User)) -> Result<(), ApiError> {
+        let mut users = self.users();
+        let user = users.get_mut(&id).ok_or_else(not_found)?;
+        work(user);
+        user.updated_at = Utc::now().naive_utc();
+        Ok(())
+    }
+}
+
+impl UserRepository for Memory {
+    fn register(
+        &self,
+        admin: bool,
+        username: &str,
+        password: &str,
+        email: &str,
+        locale: &str,
+    ) -> Result<i32, ApiError> {
+        let password_hash = hash(format!("{}{}", email, password), COST)?;
+        let mut users = self.users();
+        if users.values().any(|user| user.email == email) {
+            return Err(ApiError::InternalError("The user email exist".to_owned()));
+        }
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let now = Utc::now().naive_utc();
+        users.insert(
+            id,
+            User {
+                id,
+                is_admin: admin,
+                username: username.to_owned(),
+                email: email.to_owned(),
+                token_key: user::new_token_key(),
+                password_hash,
+                reset_token: String::new(),
+                created_at: now,
+                updated_at: now,
+                locale: locale.to_owned(),
+                is_active: true,
+            },
+        );
+        Ok(id)
+    }
+
+    fn update(
+        &self,
+        id: i32,
+        username: &str,
+        password: &str,
+        email: &str,
+        locale: Option<&str>,
+    ) -> Result<(), ApiError> {
+        let password_hash = hash(format!("{}{}", email, password), COST)?;
+        let mut users = self.users();
+        let user = users.get_mut(&id).ok_or_else(not_found)?;
+        user.username = username.to_owned();
+        user.email = email.to_owned();
+        user.password_hash = password_hash;
+        user.token_key = user::new_token_key();
+        if let Some(locale) = locale {
+            user.locale = locale.to_owned();
+        }
+        user.updated_at = Utc::now().naive_utc();
+        Ok(())
+    }
+
+    fn delete(&self, id: i32) -> Result<(), ApiError> {
+        self.users().remove(&id);
+        Ok(())
+    }
+
+    fn get_by_id(&self, id: i32) -> Result<User, ApiError> {
+        self.cloned(id)
+    }
+
+    fn get_by_email(&self, email: &str) -> Result<User, ApiError> {
+        self.with_email(email, |user| user.clone())
+    }
+
+    fn profile(&self, id: i32) -> Result<User, ApiError> {
+        self.cloned(id)
+    }
+
+    fn search(&self, query: &str) -> Result<Vec<User>, ApiError> {
+        let query = query.to_lowercase();
+        Ok(self
+            .users()
+            .values()
+            .filter(|user| {
+                user.username.to_lowercase().contains(&query)
+                    || user.email.to_lowercase().contains(&query)
+            })
+            .take(100)
+            .cloned()
+            .collect())
+    }
+
+    fn update_profile(&self, id: i32, username: &str, locale: &str) -> Result<(), ApiError> {
+        self.with_id(id, |user| {
+            user.username = username.to_owned();
+            user.locale = locale.to_owned();
+        })
+    }
+
+    fn set_admin(&self, id: i32, admin: bool) -> Result<(), ApiError> {
+        self.with_id(id, |user| user.is_admin = admin)
+    }
+
+    fn set_active(&self, id: i32, active: bool) -> Result<(), ApiError> {
+        self.with_id(id, |user| user.is_active = active)
+    }
+}
+
+impl SessionRepository for Memory {
+    fn auth(&self, email: &str, password: &str) -> Result<String, ApiError> {
+        let user = self.get_by_email(email)?;
+        if !user.is_active {
+            return Err(ApiError::InternalError("User is deactivated".to_owned()));
+        }
+        match verify(format!("{}{}", email, password), &user.password_hash)? {
+            true => user::create_token(user),
+            _ => Err(ApiError::InternalError(
+                "Invalid email or password".to_owned(),
+            )),
+        }
+    }
+
+    fn admin_auth(&self, email: &str, password: &str) -> Result<String, ApiError> {
+        match self.get_by_email(email)?.is_admin {
+            true => self.auth(email, password),
+            _ => Err(ApiError::InternalError("User is not admin".to_owned())),
+        }
+    }
+
+    fn create_token(&self, id: i32) -> Result<String, ApiError> {
+        user::create_token(self.cloned(id)?)
+    }
+
+    fn session_key(&self, id: i32) -> Result<Option<SessionKey>, ApiError> {
+        Ok(self.users().get(&id).map(|user| SessionKey {
+            token_key: user.token_key.clone(),
+            is_admin: user.is_admin,
+            is_active: user.is_active,
+        }))
+    }
+}
+
+impl TokenRepository for Memory {
+    fn generate_reset_token(&self, email: &str) -> Result<String, ApiError> {
+        self.with_email(email, |_| ())?;
+        let token = random(6);
+        self.reset_tokens
+            .lock()
+            .unwrap()
+            .insert(email.to_owned(), token.clone());
+        Ok(token)
+    }
+
+    fn verify_reset_token(&self, email: &str, token: &str) -> Result<(), ApiError> {
+        match self.reset_tokens.lock().unwrap().get(email) {
+            Some(expected) if expected == token => Ok(()),
+            _ => Err(ApiError::InternalError("Invalid reset token".to_owned())),
+        }
+    }
+
+    fn change_password(&self, email: &str, password: &str) -> Result<(), ApiError> {
+        let password_hash = hash(format!("{}{}", email, password), COST)?;
+        self.with_email(email, |user| {
+            user.password_hash = password_hash;
+            user.token_key = user::new_token_key();
+        })
+    }
+}
@@ -0,0 +1,259 @@
+use diesel::connection::SimpleConnection;
+use diesel::Connection;
+use diesel_migrations::{
+    revert_latest_migration_in_directory, run_migration_with_version, run_migrations,
+    setup_database, MigrationConnection,
+};
+use log::info;
+use std::io;
+use std::path::Path;
+
+use super::{ConnectionSetup, DbConnection};
+use crate::errors::ApiError;
+use crate::settings::Settings;
+
+/// The SQL of the backend's migrations, embedded at build time by diesel_migrations
+#[allow(dead_code)]
+mod embedded {
+    use diesel_migrations::EmbedMigrations;
+
+    #[cfg(feature = "postgres")]
+    #[derive(EmbedMigrations)]
+    #[embed_migrations_options(migrations_path = "migrations")]
+    struct Postgres;
+
+    #[cfg(feature = "sqlite")]
+    #[derive(EmbedMigrations)]
+    #[embed_migrations_options(migrations_path = "migrations_sqlite")]
+    struct Sqlite;
+
+    /// Every embedded migration, oldest first
+    pub fn all() -> &'static [&'static dyn Migration] {
+        ALL_MIGRATIONS
+    }
+}
+
+/// Where the SQL of the migrations is, relative to the crate. The binary embeds
+/// the `up.sql` files; reverting reads the `down.sql` ones from there, like diesel_cli.
+#[cfg(feature = "postgres")]
+#[allow(dead_code)] // used by artisan
+pub const DIRECTORY: &str = "migrations";
+#[cfg(feature = "sqlite")]
+#[allow(dead_code)] // used by artisan
+pub const DIRECTORY: &str = "migrations_sqlite";
+
+/// Key of the advisory lock taken while migrating, so instances starting together
+/// don't run the same migration twice
+#[cfg(feature = "postgres")]
+const LOCK: i64 = 0x736b_656c_6574_6f6e;
+
+/// What the API does with the pending migrations when it starts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// run them
+    Run,
+    /// refuse to start while there are some
+    Verify,
+    Ignore,
+}
+
+impl std::str::FromStr for Policy {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "run" => Ok(Policy::Run),
+            "verify" => Ok(Policy::Verify),
+            "ignore" => Ok(Policy::Ignore),
+            _ => Err(ApiError::InternalError(format!(
+                "Unknown migrations policy : {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Versions of the embedded migrations, oldest first
+pub fn versions() -> Vec<&'static str> {
+    embedded::all()
+        .iter()
+        .map(|migration| migration.version())
+        .collect()
+}
+
+/// Every embedded migration, and whether it ran
+pub fn status(conn: &DbConnection) -> Result<Vec<(&'static str, bool)>, ApiError> {
+    setup_database(conn)?;
+    let applied = conn.previously_run_migration_versions()?;
+    Ok(versions()
+        .into_iter()
+        .map(|version| (version, applied.contains(version)))
+        .collect())
+}
+
+/// The embedded migrations not run yet, oldest first
+pub fn pending(conn: &DbConnection) -> Result<Vec<&'static str>, ApiError> {
+    Ok(status(conn)?
+        .into_iter()
+        .filter(|(_, done)| !done)
+        .map(|(version, _)| version)
+        .collect())
+}
+
+/// Run `work` holding the migration lock
+#[cfg(feature = "postgres")]
+fn locked<T, F>(conn: &DbConnection, work: F) -> Result<T, ApiError>
+where
+    F: FnOnce() -> Result<T, ApiError>,
+{
+    conn.batch_execute(&format!("SELECT pg_advisory_lock({});", LOCK))?;
+    let result = work();
+    conn.batch_execute(&format!("SELECT pg_advisory_unlock({});", LOCK))?;
+    result
+}
+
+/// SQLite serves a single host, nothing else migrates it meanwhile
+#[cfg(feature = "sqlite")]
+fn locked<T, F>(_: &DbConnection, work: F) -> Result<T, ApiError>
+where
+    F: FnOnce() -> Result<T, ApiError>,
+{
+    work()
+}
+
+/// The connection to migrate `database_url` with, set up like those of the pool but
+/// without their statement timeout on Postgres : a migration may take long, and so
+/// may waiting for another instance to finish migrating
+pub fn connect(settings: &Settings) -> Result<DbConnection, ApiError> {
+    let conn = DbConnection::establish(&settings.database_url)?;
+    conn.batch_execute(&setup(settings).sql())?;
+    Ok(conn)
+}
+
+fn setup(settings: &Settings) -> ConnectionSetup {
+    ConnectionSetup {
+        // on SQLite, how long to wait for the database locked by the api
+        #[cfg(feature = "sqlite")]
+        statement_timeout: settings.db_statement_timeout,
+        #[cfg(feature = "postgres")]
+        statement_timeout: 0,
+        application_name: settings.db_application_name.clone(),
+        read_only: false,
+    }
+}
+
+/// Run the pending migrations, each in its own transaction, and return their versions
+pub fn run_pending(conn: &DbConnection) -> Result<Vec<&'static str>, ApiError> {
+    locked(conn, || {
+        let pending = pending(conn)?;
+        run_migrations(conn, embedded::all().iter().copied(), &mut io::sink())?;
+        for version in &pending {
+            info!("Migration {} ran", version);
+        }
+        Ok(pending)
+    })
+}
+
+/// Revert the latest migration run with the `down.sql` of `directory`, none when nothing ran
+#[allow(dead_code)] // used by artisan
+pub fn revert_last(conn: &DbConnection, directory: &Path) -> Result<Option<String>, ApiError> {
+    locked(conn, || {
+        setup_database(conn)?;
+        if conn.latest_run_migration_version()?.is_none() {
+            return Ok(None);
+        }
+        let version = revert_latest_migration_in_directory(conn, directory)?;
+        info!("Migration {} reverted", version);
+        Ok(Some(version))
+    })
+}
+
+/// Revert then run again the latest migration, to check its down.sql
+#[allow(dead_code)] // used by artisan
+pub fn redo(conn: &DbConnection, directory: &Path) -> Result<Option<String>, ApiError> {
+    let version = revert_last(conn, directory)?;
+    if let Some(version) = &version {
+        locked(conn, || {
+            run_migration_with_version(conn, directory, version, &mut io::sink())?;
+            Ok(())
+        })?;
+    }
+    Ok(version)
+}
+
+/// Apply the `migrations` policy, before the API serves anything
+pub fn from_settings(settings: &Settings) -> Result<(), ApiError> {
+    let conn = connect(settings)?;
+    match settings.migrations.parse()? {
+        Policy::Run => {
+            let ran = run_pending(&conn)?;
+            info!("{} migrations ran, the schema is up to date", ran.len());
+            Ok(())
+        }
+        Policy::Verify => match pending(&conn)? {
+            pending if pending.is_empty() => Ok(()),
+            pending => Err(ApiError::InternalError(format!(
+                "Pending migrations, run them with artisan : {}",
+                pending.join(", ")
+            ))),
+        },
+        Policy::Ignore => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_are_embedded_in_order() {
+        let versions = versions();
+        assert!(versions.contains(&"20201108143726"));
+        assert!(versions.contains(&"20261018090000"));
+        let mut sorted = versions.clone();
+        sorted.sort_unstable();
+        assert_eq!(versions, sorted);
+
+        assert_eq!("Verify".parse::<Policy>().unwrap(), Policy::Verify);
+        assert!("sometimes".parse::<Policy>().is_err());
+    }
+
+    /// the migrations may run longer than the requests, and wait for another instance
+    #[cfg(feature = "postgres")]
+    #[test]
+    fn postgres_migrations_have_no_statement_timeout() {
+        let settings = crate::settings::parse(crate::settings::MINIMAL).unwrap();
+        assert!(settings.db_statement_timeout > 0);
+        assert!(setup(&settings)
+            .sql()
+            .starts_with("SET statement_timeout = 0;"));
+    }
+
+    /// the SQLite build runs its migrations and the user queries on a database in memory
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_schema_migrates_both_ways() {
+        use crate::db::user;
+
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join(DIRECTORY);
+        let conn = DbConnection::establish(":memory:").unwrap();
+        assert_eq!(run_pending(&conn).unwrap().len(), versions().len());
+        assert!(pending(&conn).unwrap().is_empty());
+
+        let id = user::register(false, "Ada", "secret", "ada@example.com", "fr", &conn).unwrap();
+        user::change_password("ada@example.com", "other", &conn).unwrap();
+        assert!(user::auth("ada@example.com", "other", &conn).is_ok());
+        let found = user::search("ADA", &conn).unwrap();
+        assert_eq!(found.iter().map(|u| u.id).collect::<Vec<_>>(), [id]);
+        assert_eq!(found[0].locale, "fr");
+
+        let latest = versions().last().copied().map(str::to_owned);
+        assert_eq!(redo(&conn, &directory).unwrap(), latest);
+        assert!(pending(&conn).unwrap().is_empty());
+        for _ in versions() {
+            assert!(revert_last(&conn, &directory).unwrap().is_some());
+        }
+        assert_eq!(revert_last(&conn, &directory).unwrap(), None);
+        run_pending(&conn).unwrap();
+    }
+}
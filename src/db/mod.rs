@@ -1,18 +1,315 @@
+pub mod cache;
+#[cfg(test)]
+pub mod memory;
+pub mod migrations;
 pub mod models;
+pub mod repository;
 pub mod schema;
 pub mod user;
 
-use std::env;
+use actix_web::web;
+use diesel::connection::SimpleConnection;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Error as R2D2Error, Pool};
+use diesel::{sql_query, RunQueryDsl};
+use log::{info, warn};
+use r2d2::event::{CheckoutEvent, HandleEvent, TimeoutEvent};
 
-use diesel::pg::PgConnection;
-//use diesel::mysql::MysqlConnection;
-use diesel::r2d2::{ConnectionManager, Pool, PoolError};
+use std::ops::Deref;
+use std::thread;
+use std::time::Duration;
+use tracing::Span;
 
-pub type DbConnection = PgConnection;
+use crate::errors::ApiError;
+use crate::metrics::{DB_POOL_TIMEOUTS, DB_POOL_WAIT};
+use crate::settings::{redact_url, Settings};
+
+#[cfg(all(feature = "postgres", feature = "sqlite"))]
+compile_error!("Build with a single database backend : --no-default-features --features sqlite");
+#[cfg(not(any(feature = "postgres", feature = "sqlite")))]
+compile_error!("Build with a database backend : the postgres or the sqlite feature");
+
+#[cfg(feature = "postgres")]
+pub type DbConnection = diesel::pg::PgConnection;
+#[cfg(feature = "sqlite")]
+pub type DbConnection = diesel::sqlite::SqliteConnection;
 pub type DbPool = Pool<ConnectionManager<DbConnection>>;
 
-pub fn init_pool() -> Result<DbPool, PoolError> {
-    let url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let manager = ConnectionManager::<DbConnection>::new(url);
-    Pool::builder().build(manager)
+/// Pool of the read-only queries, on the replica when there is one, the primary otherwise.
+/// The replica may lag behind : what must see a write just made reads the primary.
+#[derive(Clone)]
+pub struct ReadPool(pub DbPool);
+
+impl Deref for ReadPool {
+    type Target = DbPool;
+
+    fn deref(&self) -> &DbPool {
+        &self.0
+    }
+}
+
+/// The backend, as OpenTelemetry names it in `db.system`
+#[cfg(feature = "postgres")]
+pub const SYSTEM: &str = "postgresql";
+#[cfg(feature = "sqlite")]
+pub const SYSTEM: &str = "sqlite";
+
+/// Longest wait between two connection attempts at startup
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Records how long requests wait for a connection
+#[derive(Debug)]
+struct PoolMetrics;
+
+impl HandleEvent for PoolMetrics {
+    fn handle_checkout(&self, event: CheckoutEvent) {
+        DB_POOL_WAIT.observe(&[], event.duration().as_secs_f64());
+    }
+
+    fn handle_timeout(&self, _: TimeoutEvent) {
+        DB_POOL_TIMEOUTS.inc(&[]);
+    }
+}
+
+/// Sets up every new connection of a pool
+#[derive(Debug)]
+struct ConnectionSetup {
+    /// in milliseconds, 0 for none. SQLite has no statement timeout : it is how
+    /// long a query waits for the database locked by another connection.
+    statement_timeout: u64,
+    #[cfg_attr(feature = "sqlite", allow(dead_code))]
+    application_name: String,
+    read_only: bool,
+}
+
+impl ConnectionSetup {
+    #[cfg(feature = "postgres")]
+    fn sql(&self) -> String {
+        let mut sql = format!(
+            "SET statement_timeout = {}; SET application_name = '{}';",
+            self.statement_timeout,
+            self.application_name.replace('\'', "''")
+        );
+        if self.read_only {
+            sql.push_str(" SET default_transaction_read_only = on;");
+        }
+        sql
+    }
+
+    /// WAL lets the pool read while a connection writes
+    #[cfg(feature = "sqlite")]
+    fn sql(&self) -> String {
+        let mut sql = format!(
+            "PRAGMA busy_timeout = {}; PRAGMA foreign_keys = ON; PRAGMA journal_mode = WAL;",
+            self.statement_timeout
+        );
+        if self.read_only {
+            sql.push_str(" PRAGMA query_only = ON;");
+        }
+        sql
+    }
+}
+
+impl CustomizeConnection<DbConnection, R2D2Error> for ConnectionSetup {
+    fn on_acquire(&self, conn: &mut DbConnection) -> Result<(), R2D2Error> {
+        conn.batch_execute(&self.sql())
+            .map_err(R2D2Error::QueryError)
+    }
+}
+
+/// Wait before the attempt following the `attempt`th failed one, doubling each time
+fn backoff(initial: Duration, attempt: u32) -> Duration {
+    initial
+        .checked_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .unwrap_or(MAX_BACKOFF)
+        .min(MAX_BACKOFF)
+}
+
+fn build_pool(url: &str, settings: &Settings, read_only: bool) -> Result<DbPool, ApiError> {
+    let seconds = |s: u64| match s {
+        0 => None,
+        s => Some(Duration::from_secs(s)),
+    };
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let pool = Pool::builder()
+            .max_size(settings.db_pool_max_size)
+            .min_idle(settings.db_pool_min_idle)
+            .connection_timeout(Duration::from_secs(settings.db_connection_timeout))
+            .idle_timeout(seconds(settings.db_idle_timeout))
+            .max_lifetime(seconds(settings.db_max_lifetime))
+            .event_handler(Box::new(PoolMetrics))
+            .connection_customizer(Box::new(ConnectionSetup {
+                statement_timeout: settings.db_statement_timeout,
+                application_name: settings.db_application_name.clone(),
+                read_only,
+            }))
+            .build(ConnectionManager::<DbConnection>::new(url));
+        match pool {
+            Ok(pool) => return Ok(pool),
+            Err(e) if attempt <= settings.db_connect_retries => {
+                let wait = backoff(Duration::from_millis(settings.db_connect_backoff), attempt);
+                warn!(
+                    "Database {} unavailable ({}), attempt {} of {}, retrying in {:?}",
+                    redact_url(url),
+                    e,
+                    attempt,
+                    settings.db_connect_retries + 1,
+                    wait
+                );
+                thread::sleep(wait);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// The pool of `database_url`, sized and tuned by the `db_*` settings.
+///
+/// The database may still be starting, e.g. next to the API in a compose file, so
+/// the connection is attempted `db_connect_retries` more times, waiting longer each time.
+pub fn from_settings(settings: &Settings) -> Result<DbPool, ApiError> {
+    build_pool(&settings.database_url, settings, false)
+}
+
+/// The pool of `database_replica_url` for the read-only queries, `primary` when not set
+pub fn replica_from_settings(settings: &Settings, primary: &DbPool) -> Result<ReadPool, ApiError> {
+    match &settings.database_replica_url {
+        Some(url) => {
+            info!("Read-only queries go to the replica {}", redact_url(url));
+            Ok(ReadPool(build_pool(url, settings, true)?))
+        }
+        None => Ok(ReadPool(primary.clone())),
+    }
+}
+
+/// Run `work` with a connection of the pool on the blocking thread pool.
+///
+/// Queries, bcrypt and waiting for a free connection all block, so they never
+/// run on an actix worker where they would stall every other request it serves.
+/// The work happens in the span of the caller.
+pub async fn run<F, T>(pool: &DbPool, work: F) -> Result<T, ApiError>
+where
+    F: FnOnce(&DbConnection) -> Result<T, ApiError> + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.clone();
+    let span = Span::current();
+    Ok(web::block(move || {
+        let _entered = span.enter();
+        let conn = pool.get()?;
+        work(&conn)
+    })
+    .await?)
+}
+
+/// Run a trivial query, to know the database answers
+pub fn ping(conn: &DbConnection) -> Result<(), ApiError> {
+    sql_query("SELECT 1").execute(conn)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_are_set_up() {
+        let setup = ConnectionSetup {
+            statement_timeout: 30000,
+            application_name: "skeleton's api".to_owned(),
+            read_only: true,
+        };
+        #[cfg(feature = "postgres")]
+        assert_eq!(
+            setup.sql(),
+            "SET statement_timeout = 30000; SET application_name = 'skeleton''s api'; \
+             SET default_transaction_read_only = on;"
+        );
+        #[cfg(feature = "sqlite")]
+        assert_eq!(
+            setup.sql(),
+            "PRAGMA busy_timeout = 30000; PRAGMA foreign_keys = ON; PRAGMA journal_mode = WAL; \
+             PRAGMA query_only = ON;"
+        );
+
+        let initial = Duration::from_millis(500);
+        let waits: Vec<Duration> = (1..=4).map(|attempt| backoff(initial, attempt)).collect();
+        assert_eq!(waits, [500, 1000, 2000, 4000].map(Duration::from_millis));
+        assert_eq!(backoff(initial, 40), MAX_BACKOFF);
+    }
+    use actix_web::{test, App, HttpResponse};
+    use futures::future::join_all;
+    use std::time::{Duration, Instant};
+
+    /// a login's worth of work, bcrypt costs far more than its queries
+    fn login_work(hash: &str) -> Result<(), ApiError> {
+        bcrypt::verify("secret", hash)?;
+        Ok(())
+    }
+
+    async fn on_worker(hash: web::Data<String>) -> Result<HttpResponse, ApiError> {
+        login_work(&hash)?;
+        Ok(HttpResponse::Ok().finish())
+    }
+
+    async fn on_blocking_pool(hash: web::Data<String>) -> Result<HttpResponse, ApiError> {
+        let hash = hash.get_ref().clone();
+        web::block(move || login_work(&hash)).await?;
+        Ok(HttpResponse::Ok().finish())
+    }
+
+    /// Time taken by `logins` concurrent requests to `path`, and by a trivial
+    /// request sent while they are handled, on a single worker
+    async fn load(path: &str, logins: usize) -> (Duration, Duration) {
+        let hash = bcrypt::hash("secret", 10).unwrap();
+        let srv = test::start(move || {
+            App::new()
+                .data(hash.clone())
+                .route("/worker", web::post().to(on_worker))
+                .route("/blocking", web::post().to(on_blocking_pool))
+                .route("/ping", web::get().to(HttpResponse::Ok))
+        });
+
+        let started = Instant::now();
+        let logins = join_all((0..logins).map(|_| srv.post(path).send()));
+        let ping = async {
+            actix_web::rt::time::delay_for(Duration::from_millis(10)).await;
+            let sent = Instant::now();
+            assert!(srv.get("/ping").send().await.unwrap().status().is_success());
+            sent.elapsed()
+        };
+        let (responses, ping) = futures::join!(logins, ping);
+        assert!(responses
+            .iter()
+            .all(|res| res.as_ref().unwrap().status().is_success()));
+        (started.elapsed(), ping)
+    }
+
+    /// Measures, nothing to check : `cargo test --release -- --ignored --nocapture blocking_pool_benchmark`
+    #[actix_rt::test]
+    #[ignore]
+    async fn blocking_pool_benchmark() {
+        const LOGINS: usize = 16;
+        let (worker, worker_ping) = load("/worker", LOGINS).await;
+        let (blocking, blocking_ping) = load("/blocking", LOGINS).await;
+
+        for (name, total, ping) in &[
+            ("on the worker", worker, worker_ping),
+            ("on the blocking pool", blocking, blocking_ping),
+        ] {
+            println!(
+                "{} logins {} : {:.1} per second, a request sent meanwhile took {:?}",
+                LOGINS,
+                name,
+                LOGINS as f64 / total.as_secs_f64(),
+                ping
+            );
+        }
+        // timings depend on the machine, they are reported rather than asserted
+        println!(
+            "the request sent meanwhile was {:.1} times faster with bcrypt on the blocking pool",
+            worker_ping.as_secs_f64() / blocking_ping.as_secs_f64().max(f64::EPSILON)
+        );
+    }
 }
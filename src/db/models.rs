@@ -1,7 +1,7 @@
 use super::schema::users;
 use chrono::NaiveDateTime;
 
-#[derive(Serialize, Queryable, Debug)]
+#[derive(Serialize, Queryable, Debug, Clone)]
 pub struct User {
     pub id: i32,
     pub is_admin: bool,
@@ -15,6 +15,8 @@ pub struct User {
     pub reset_token: String,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub locale: String,
+    pub is_active: bool,
 }
 
 #[derive(Insertable)]
@@ -26,4 +28,5 @@ pub struct NewUser<'a> {
     pub token_key: &'a str,
     pub password_hash: &'a str,
     pub reset_token: &'a str,
+    pub locale: &'a str,
 }
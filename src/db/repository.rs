@@ -0,0 +1,227 @@
+use actix_web::web;
+use std::sync::Arc;
+use tracing::Span;
+
+use super::cache::{SessionKey, SessionKeys};
+use super::models::User;
+use super::{user, DbPool, ReadPool};
+use crate::errors::ApiError;
+
+/// The users, as the handlers see them
+pub trait UserRepository: Send + Sync {
+    /// Create a user, returning its id
+    fn register(
+        &self,
+        admin: bool,
+        username: &str,
+        password: &str,
+        email: &str,
+        locale: &str,
+    ) -> Result<i32, ApiError>;
+
+    /// Replace the name, password and email of a user, and its locale when given
+    fn update(
+        &self,
+        id: i32,
+        username: &str,
+        password: &str,
+        email: &str,
+        locale: Option<&str>,
+    ) -> Result<(), ApiError>;
+
+    fn delete(&self, id: i32) -> Result<(), ApiError>;
+
+    fn get_by_id(&self, id: i32) -> Result<User, ApiError>;
+
+    fn get_by_email(&self, email: &str) -> Result<User, ApiError>;
+
+    /// A user to display, which may lag behind the latest writes
+    fn profile(&self, id: i32) -> Result<User, ApiError>;
+
+    /// Users whose name or email contains `query`, ignoring the case, all of them when it is empty
+    fn search(&self, query: &str) -> Result<Vec<User>, ApiError>;
+
+    /// Replace the name and locale of a user, as an admin does
+    fn update_profile(&self, id: i32, username: &str, locale: &str) -> Result<(), ApiError>;
+
+    fn set_admin(&self, id: i32, admin: bool) -> Result<(), ApiError>;
+
+    fn set_active(&self, id: i32, active: bool) -> Result<(), ApiError>;
+}
+
+/// The session tokens of the users
+pub trait SessionRepository: Send + Sync {
+    /// Check the credentials, returning a new session token
+    fn auth(&self, email: &str, password: &str) -> Result<String, ApiError>;
+
+    /// Like `auth`, for the admins only
+    fn admin_auth(&self, email: &str, password: &str) -> Result<String, ApiError>;
+
+    /// A new session token for a user
+    fn create_token(&self, id: i32) -> Result<String, ApiError>;
+
+    /// What verifying a session token of a user needs, none for an unknown user
+    fn session_key(&self, id: i32) -> Result<Option<SessionKey>, ApiError>;
+}
+
+/// The reset tokens and passwords of the users
+pub trait TokenRepository: Send + Sync {
+    /// Give a user a new reset token, returning it
+    fn generate_reset_token(&self, email: &str) -> Result<String, ApiError>;
+
+    fn verify_reset_token(&self, email: &str, token: &str) -> Result<(), ApiError>;
+
+    fn change_password(&self, email: &str, password: &str) -> Result<(), ApiError>;
+}
+
+/// The repositories over diesel, with a connection of the pool for every call.
+/// Users they change leave `session_keys`, the cache of the session middleware.
+#[derive(Clone)]
+pub struct Diesel {
+    pool: DbPool,
+    read_pool: ReadPool,
+    session_keys: web::Data<SessionKeys>,
+}
+
+impl Diesel {
+    pub fn new(pool: DbPool, read_pool: ReadPool, session_keys: web::Data<SessionKeys>) -> Self {
+        Diesel {
+            pool,
+            read_pool,
+            session_keys,
+        }
+    }
+}
+
+impl UserRepository for Diesel {
+    fn register(
+        &self,
+        admin: bool,
+        username: &str,
+        password: &str,
+        email: &str,
+        locale: &str,
+    ) -> Result<i32, ApiError> {
+        user::register(admin, username, password, email, locale, &*self.pool.get()?)
+    }
+
+    fn update(
+        &self,
+        id: i32,
+        username: &str,
+        password: &str,
+        email: &str,
+        locale: Option<&str>,
+    ) -> Result<(), ApiError> {
+        user::update(&id, username, password, email, locale, &*self.pool.get()?)?;
+        self.session_keys.invalidate(id);
+        Ok(())
+    }
+
+    fn delete(&self, id: i32) -> Result<(), ApiError> {
+        user::delete(&id, &*self.pool.get()?)?;
+        self.session_keys.invalidate(id);
+        Ok(())
+    }
+
+    fn get_by_id(&self, id: i32) -> Result<User, ApiError> {
+        user::get_user_by_id(&id, &*self.pool.get()?)
+    }
+
+    fn get_by_email(&self, email: &str) -> Result<User, ApiError> {
+        user::get_user_by_email(email, &*self.pool.get()?)
+    }
+
+    fn profile(&self, id: i32) -> Result<User, ApiError> {
+        user::get_user_by_id(&id, &*self.read_pool.get()?)
+    }
+
+    fn search(&self, query: &str) -> Result<Vec<User>, ApiError> {
+        user::search(query, &*self.read_pool.get()?)
+    }
+
+    fn update_profile(&self, id: i32, username: &str, locale: &str) -> Result<(), ApiError> {
+        user::update_profile(&id, username, locale, &*self.pool.get()?)
+    }
+
+    fn set_admin(&self, id: i32, admin: bool) -> Result<(), ApiError> {
+        user::set_admin(&id, admin, &*self.pool.get()?)?;
+        self.session_keys.invalidate(id);
+        Ok(())
+    }
+
+    fn set_active(&self, id: i32, active: bool) -> Result<(), ApiError> {
+        user::set_active(&id, active, &*self.pool.get()?)?;
+        self.session_keys.invalidate(id);
+        Ok(())
+    }
+}
+
+impl SessionRepository for Diesel {
+    fn auth(&self, email: &str, password: &str) -> Result<String, ApiError> {
+        user::auth(email, password, &*self.pool.get()?)
+    }
+
+    fn admin_auth(&self, email: &str, password: &str) -> Result<String, ApiError> {
+        let db = self.pool.get()?;
+        match user::get_user_by_email(email, &db)?.is_admin {
+            true => user::auth(email, password, &db),
+            _ => Err(ApiError::InternalError("User is not admin".to_owned())),
+        }
+    }
+
+    fn create_token(&self, id: i32) -> Result<String, ApiError> {
+        user::create_token(user::get_user_by_id(&id, &*self.pool.get()?)?)
+    }
+
+    fn session_key(&self, id: i32) -> Result<Option<SessionKey>, ApiError> {
+        user::get_session_key(&id, &*self.pool.get()?)
+    }
+}
+
+impl TokenRepository for Diesel {
+    fn generate_reset_token(&self, email: &str) -> Result<String, ApiError> {
+        user::generate_reset_token(email, &*self.pool.get()?)
+    }
+
+    fn verify_reset_token(&self, email: &str, token: &str) -> Result<(), ApiError> {
+        user::verify_reset_token(email, token, &*self.pool.get()?)
+    }
+
+    fn change_password(&self, email: &str, password: &str) -> Result<(), ApiError> {
+        let id = user::change_password(email, password, &*self.pool.get()?)?;
+        self.session_keys.invalidate(id);
+        Ok(())
+    }
+}
+
+/// Give the handlers `repository` as each of the repositories, in the app data
+pub fn configure<R>(repository: Arc<R>) -> impl FnOnce(&mut web::ServiceConfig)
+where
+    R: UserRepository + SessionRepository + TokenRepository + 'static,
+{
+    move |cfg| {
+        let users: Arc<dyn UserRepository> = repository.clone();
+        let sessions: Arc<dyn SessionRepository> = repository.clone();
+        let tokens: Arc<dyn TokenRepository> = repository;
+        cfg.app_data(web::Data::from(users))
+            .app_data(web::Data::from(sessions))
+            .app_data(web::Data::from(tokens));
+    }
+}
+
+/// Run `work` with a repository on the blocking thread pool, like `db::run`
+pub async fn run<R, F, T>(repository: &web::Data<R>, work: F) -> Result<T, ApiError>
+where
+    R: ?Sized + Send + Sync + 'static,
+    F: FnOnce(&R) -> Result<T, ApiError> + Send + 'static,
+    T: Send + 'static,
+{
+    let repository = repository.clone();
+    let span = Span::current();
+    Ok(web::block(move || {
+        let _entered = span.enter();
+        work(&repository)
+    })
+    .await?)
+}
@@ -9,5 +9,7 @@ table! {
         reset_token -> Text,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        locale -> Varchar,
+        is_active -> Bool,
     }
 }
@@ -1,3 +1,4 @@
+use super::cache::SessionKey;
 use super::DbConnection;
 use super::{models::*, schema::users, schema::users::dsl::*};
 
@@ -13,28 +14,39 @@ extern crate bcrypt;
 use bcrypt::{hash, verify, DEFAULT_COST};
 
 extern crate branca;
-use branca::Branca;
+use branca::{errors::Error as BrancaError, Branca};
+use derive_more::Display;
+use tracing::info_span;
 
 extern crate rand;
 use rand::{distributions::Alphanumeric, Rng};
 
+/// A random key signing the session and reset tokens of a user.
+/// Replacing it revokes every token of the user.
+pub fn new_token_key() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .collect::<String>()
+}
+
 pub fn register(
     admin: bool,
     user: &str,
     pwd: &str,
     mail: &str,
+    lang: &str,
     db: &DbConnection,
 ) -> Result<i32, ApiError> {
-    if let true = diesel::select(exists(users.filter(email.eq(mail)))).get_result(db)? {
+    let _span = info_span!("db.user.register", db.system = super::SYSTEM).entered();
+    if diesel::select(exists(users.filter(email.eq(mail)))).get_result(db)? {
         return Err(ApiError::InternalError("The user email exist".to_owned()));
     }
 
-    let key = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(32)
-        .collect::<String>();
+    let key = new_token_key();
 
-    let hash = hash(format!("{}{}", mail, pwd), DEFAULT_COST)?;
+    let hash =
+        info_span!("bcrypt.hash").in_scope(|| hash(format!("{}{}", mail, pwd), DEFAULT_COST))?;
 
     let new_user = NewUser {
         is_admin: &admin,
@@ -43,13 +55,15 @@ pub fn register(
         token_key: &key,
         password_hash: &hash,
         reset_token: "",
+        locale: lang,
     };
 
-    let created_user: User = diesel::insert_into(users::table)
+    // SQLite can't return the inserted row, the email finds it back
+    diesel::insert_into(users::table)
         .values(&new_user)
-        .get_result(db)?;
+        .execute(db)?;
 
-    Ok(created_user.id)
+    Ok(users.filter(email.eq(mail)).select(id).first(db)?)
 }
 
 pub fn update(
@@ -57,36 +71,99 @@ pub fn update(
     user: &str,
     pwd: &str,
     mail: &str,
+    lang: Option<&str>,
     db: &DbConnection,
 ) -> Result<(), ApiError> {
+    let _span = info_span!("db.user.update", db.system = super::SYSTEM).entered();
     let current_user = get_user_by_id(_id, db)?;
-    let hash = hash(format!("{}{}", mail, pwd), DEFAULT_COST)?;
+    let hash =
+        info_span!("bcrypt.hash").in_scope(|| hash(format!("{}{}", mail, pwd), DEFAULT_COST))?;
+    let lang = lang.unwrap_or(&current_user.locale);
 
     diesel::update(users.find(current_user.id))
-        .set((email.eq(mail), password_hash.eq(hash), username.eq(user)))
+        .set((
+            email.eq(mail),
+            password_hash.eq(hash),
+            username.eq(user),
+            locale.eq(lang),
+            // the password changed, the sessions opened with the old one end
+            token_key.eq(new_token_key()),
+        ))
         .execute(db)?;
 
     Ok(())
 }
 
 pub fn delete(_id: &i32, db: &DbConnection) -> Result<(), ApiError> {
+    let _span = info_span!("db.user.delete", db.system = super::SYSTEM).entered();
     diesel::delete(users.filter(id.eq(_id))).execute(db)?;
     Ok(())
 }
 
 pub fn get_user_by_id(_id: &i32, db: &DbConnection) -> Result<User, ApiError> {
+    let _span = info_span!("db.user.get_user_by_id", db.system = super::SYSTEM).entered();
     Ok(users.filter(users::id.eq(_id)).first(db)?)
 }
 
 pub fn get_user_by_email(mail: &str, db: &DbConnection) -> Result<User, ApiError> {
+    let _span = info_span!("db.user.get_user_by_email", db.system = super::SYSTEM).entered();
     Ok(users.filter(users::email.eq(mail)).first(db)?)
 }
 
+// administration part
+
+/// Users whose name or email contains `query`, all of them when it is empty
+pub fn search(query: &str, db: &DbConnection) -> Result<Vec<User>, ApiError> {
+    let _span = info_span!("db.user.search", db.system = super::SYSTEM).entered();
+    let pattern = format!("%{}%", query);
+    // SQLite's LIKE already ignores the case
+    #[cfg(feature = "postgres")]
+    let matching = username.ilike(&pattern).or(email.ilike(&pattern));
+    #[cfg(feature = "sqlite")]
+    let matching = username.like(&pattern).or(email.like(&pattern));
+    Ok(users.filter(matching).order(id.asc()).limit(100).load(db)?)
+}
+
+pub fn update_profile(
+    _id: &i32,
+    user: &str,
+    lang: &str,
+    db: &DbConnection,
+) -> Result<(), ApiError> {
+    let _span = info_span!("db.user.update_profile", db.system = super::SYSTEM).entered();
+    diesel::update(users.find(_id))
+        .set((username.eq(user), locale.eq(lang)))
+        .execute(db)?;
+    Ok(())
+}
+
+pub fn set_admin(_id: &i32, admin: bool, db: &DbConnection) -> Result<(), ApiError> {
+    let _span = info_span!("db.user.set_admin", db.system = super::SYSTEM).entered();
+    diesel::update(users.find(_id))
+        .set(is_admin.eq(admin))
+        .execute(db)?;
+    Ok(())
+}
+
+pub fn set_active(_id: &i32, active: bool, db: &DbConnection) -> Result<(), ApiError> {
+    let _span = info_span!("db.user.set_active", db.system = super::SYSTEM).entered();
+    diesel::update(users.find(_id))
+        .set(is_active.eq(active))
+        .execute(db)?;
+    Ok(())
+}
+
 // auth and session token part
 
 pub fn auth(mail: &str, pwd: &str, db: &DbConnection) -> Result<String, ApiError> {
+    let _span = info_span!("db.user.auth", db.system = super::SYSTEM).entered();
     let user: User = users.filter(users::email.eq(mail)).first(db)?;
-    match verify(format!("{}{}", mail, pwd), &user.password_hash)? {
+    if !user.is_active {
+        return Err(ApiError::InternalError("User is deactivated".to_owned()));
+    }
+    match info_span!("bcrypt.verify")
+        .in_scope(|| verify(format!("{}{}", mail, pwd), &user.password_hash))?
+    {
         true => Ok(create_token(user)?),
         _ => Err(ApiError::InternalError(
             "Invalid email or password".to_owned(),
@@ -101,10 +178,11 @@ struct JsonBrancaToken {
 }
 
 pub fn create_token(user: User) -> Result<String, ApiError> {
+    let _span = info_span!("db.user.create_token", db.system = super::SYSTEM).entered();
     let key = user.token_key.as_bytes().to_vec();
     let mut branca = Branca::new(&key)?;
     let payload = format!("{}", Utc::now());
-    let t = branca.encode(&payload.as_bytes())?;
+    let t = branca.encode(payload.as_bytes())?;
     let token = JsonBrancaToken {
         id: user.id,
         token: t,
@@ -112,25 +190,71 @@ pub fn create_token(user: User) -> Result<String, ApiError> {
     Ok(serde_json::to_string(&token)?)
 }
 
+/// Why a session token is refused
+#[derive(Debug, Display)]
+pub enum TokenError {
+    #[display(fmt = "Unknown user")]
+    UnknownUser,
+    #[display(fmt = "User is deactivated")]
+    Deactivated,
+    #[display(fmt = "User is not admin")]
+    NotAdmin,
+    #[display(fmt = "{}", _0)]
+    Invalid(BrancaError),
+}
+
+impl TokenError {
+    /// Short name of the failure, for the metrics
+    pub fn reason(&self) -> &'static str {
+        match self {
+            TokenError::UnknownUser => "unknown_user",
+            TokenError::Deactivated => "deactivated",
+            TokenError::NotAdmin => "not_admin",
+            TokenError::Invalid(BrancaError::ExpiredToken) => "expired",
+            TokenError::Invalid(_) => "invalid",
+        }
+    }
+}
+
+/// The key and role of a user, to verify its session tokens, none for an unknown user
+pub fn get_session_key(_id: &i32, db: &DbConnection) -> Result<Option<SessionKey>, ApiError> {
+    let _span = info_span!("db.user.get_session_key", db.system = super::SYSTEM).entered();
+    let row: Option<(String, bool, bool)> = users
+        .find(_id)
+        .select((token_key, is_admin, is_active))
+        .first(db)
+        .optional()?;
+    Ok(row.map(|(key, admin, active)| SessionKey {
+        token_key: key,
+        is_admin: admin,
+        is_active: active,
+    }))
+}
+
 pub fn verify_token(
-    _id: &i32,
+    user: &SessionKey,
     token: &str,
     must_be_admin: bool,
-    db: &DbConnection,
-) -> Result<(), ApiError> {
-    let user = get_user_by_id(_id, db)?;
+    ttl: u32,
+) -> Result<(), TokenError> {
+    if !user.is_active {
+        return Err(TokenError::Deactivated);
+    }
     if must_be_admin && !user.is_admin {
-        return Err(ApiError::InternalError("User is not admin".to_owned()));
+        return Err(TokenError::NotAdmin);
     }
     let key = user.token_key.as_bytes().to_vec();
-    let branca = Branca::new(&key)?;
-    branca.decode(token, 86400)?;
+    let branca = Branca::new(&key).map_err(TokenError::Invalid)?;
+    info_span!("branca.decode")
+        .in_scope(|| branca.decode(token, ttl))
+        .map_err(TokenError::Invalid)?;
     Ok(())
 }
 
 // reset token and change password
 
 pub fn generate_reset_token(mail: &str, db: &DbConnection) -> Result<String, ApiError> {
+    let _span = info_span!("db.user.generate_reset_token", db.system = super::SYSTEM).entered();
     let user = get_user_by_email(mail, db)?;
     let rand = rand::thread_rng()
         .sample_iter(&Alphanumeric)
@@ -138,7 +262,7 @@ pub fn generate_reset_token(mail: &str, db: &DbConnection) -> Result<String, Api
         .collect::<String>();
     let key = user.token_key.as_bytes().to_vec();
     let mut branca = Branca::new(&key)?;
-    let token = branca.encode(&rand.as_bytes())?;
+    let token = branca.encode(rand.as_bytes())?;
 
     diesel::update(users.filter(email.eq(mail)))
         .set(reset_token.eq(token))
@@ -148,6 +272,7 @@ pub fn generate_reset_token(mail: &str, db: &DbConnection) -> Result<String, Api
 }
 
 pub fn verify_reset_token(mail: &str, token: &str, db: &DbConnection) -> Result<(), ApiError> {
+    let _span = info_span!("db.user.verify_reset_token", db.system = super::SYSTEM).entered();
     let user = get_user_by_email(mail, db)?;
     let key = user.token_key.as_bytes().to_vec();
     let rtoken = user.reset_token;
@@ -160,10 +285,15 @@ pub fn verify_reset_token(mail: &str, token: &str, db: &DbConnection) -> Result<
     }
 }
 
-pub fn change_password(mail: &str, pwd: &str, db: &DbConnection) -> Result<(), ApiError> {
-    let hash = hash(format!("{}{}", mail, pwd), DEFAULT_COST)?;
-    diesel::update(users.filter(email.eq(mail)))
-        .set(password_hash.eq(hash))
+/// Replace the password and the token key, ending the sessions and reset tokens
+/// of the user. Returns the id of the user.
+pub fn change_password(mail: &str, pwd: &str, db: &DbConnection) -> Result<i32, ApiError> {
+    let _span = info_span!("db.user.change_password", db.system = super::SYSTEM).entered();
+    let hash =
+        info_span!("bcrypt.hash").in_scope(|| hash(format!("{}{}", mail, pwd), DEFAULT_COST))?;
+    let user: i32 = users.filter(email.eq(mail)).select(id).first(db)?;
+    diesel::update(users.find(user))
+        .set((password_hash.eq(hash), token_key.eq(new_token_key())))
         .execute(db)?;
-    Ok(())
+    Ok(user)
 }
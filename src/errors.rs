@@ -1,21 +1,32 @@
-use actix_web::{HttpResponse, ResponseError};
+use actix::MailboxError;
+use actix_web::error::BlockingError;
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
 use derive_more::Display;
 
+use crate::db::user::TokenError;
 use bcrypt::BcryptError;
 use branca::errors::Error as BrancaError;
 use chrono::ParseError as ChronoParseError;
+use config::ConfigError;
 use diesel::result::Error as DieselError;
+use diesel::ConnectionError as DieselConnectionError;
+use diesel_migrations::RunMigrationsError;
 use lettre::smtp::error::Error as LettreSmtpError;
 use lettre_email::error::Error as LettreError;
+use native_tls::Error as TlsError;
 use r2d2::Error as R2D2Error;
 use ramhorns::Error as RamhornsError;
 use serde_json::error::Error as SerdeError;
+use serde_urlencoded::ser::Error as UrlEncodedError;
 use std::env::VarError as EnvError;
+use std::io::Error as IoError;
 use validator::ValidationErrors;
 
 #[derive(Debug, Display)]
 pub enum ApiError {
     InternalError(String),
+    /// a dependency, like the database pool, can't serve the request for now
+    Unavailable(String),
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -24,11 +35,16 @@ pub struct ErrorResponse {
 }
 
 impl ResponseError for ApiError {
-    fn error_response(&self) -> HttpResponse {
+    fn status_code(&self) -> StatusCode {
         match self {
-            _ => HttpResponse::InternalServerError().json::<ErrorResponse>(self.into()),
+            ApiError::InternalError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json::<ErrorResponse>(self.into())
+    }
 }
 
 impl From<&String> for ErrorResponse {
@@ -65,6 +81,12 @@ impl From<SerdeError> for ApiError {
     }
 }
 
+impl From<UrlEncodedError> for ApiError {
+    fn from(error: UrlEncodedError) -> ApiError {
+        ApiError::InternalError(error.to_string())
+    }
+}
+
 impl From<BrancaError> for ApiError {
     fn from(error: BrancaError) -> ApiError {
         ApiError::InternalError(error.to_string())
@@ -83,9 +105,35 @@ impl From<DieselError> for ApiError {
     }
 }
 
+impl From<DieselConnectionError> for ApiError {
+    fn from(error: DieselConnectionError) -> ApiError {
+        ApiError::Unavailable(error.to_string())
+    }
+}
+
+impl From<RunMigrationsError> for ApiError {
+    fn from(error: RunMigrationsError) -> ApiError {
+        // their Display is the deprecated description()
+        let message = match error {
+            RunMigrationsError::QueryError(e) => format!("Migration failed : {}", e),
+            e => format!("Migration failed : {:?}", e),
+        };
+        ApiError::InternalError(message)
+    }
+}
+
+impl From<BlockingError<ApiError>> for ApiError {
+    fn from(error: BlockingError<ApiError>) -> ApiError {
+        match error {
+            BlockingError::Error(error) => error,
+            BlockingError::Canceled => ApiError::InternalError("Blocking task canceled".to_owned()),
+        }
+    }
+}
+
 impl From<R2D2Error> for ApiError {
     fn from(error: R2D2Error) -> ApiError {
-        ApiError::InternalError(error.to_string())
+        ApiError::Unavailable(error.to_string())
     }
 }
 
@@ -101,6 +149,36 @@ impl From<LettreSmtpError> for ApiError {
     }
 }
 
+impl From<TlsError> for ApiError {
+    fn from(error: TlsError) -> ApiError {
+        ApiError::InternalError(error.to_string())
+    }
+}
+
+impl From<IoError> for ApiError {
+    fn from(error: IoError) -> ApiError {
+        ApiError::InternalError(error.to_string())
+    }
+}
+
+impl From<ConfigError> for ApiError {
+    fn from(error: ConfigError) -> ApiError {
+        ApiError::InternalError(error.to_string())
+    }
+}
+
+impl From<MailboxError> for ApiError {
+    fn from(error: MailboxError) -> ApiError {
+        ApiError::InternalError(error.to_string())
+    }
+}
+
+impl From<TokenError> for ApiError {
+    fn from(error: TokenError) -> ApiError {
+        ApiError::InternalError(error.to_string())
+    }
+}
+
 impl From<EnvError> for ApiError {
     fn from(error: EnvError) -> ApiError {
         ApiError::InternalError(error.to_string())
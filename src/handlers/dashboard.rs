@@ -1,9 +1,411 @@
-use actix_web::HttpResponse;
+use actix::Addr;
+use actix_web::{
+    dev::HttpResponseBuilder,
+    http::header::{CONTENT_SECURITY_POLICY, LOCATION},
+    web, HttpRequest, HttpResponse,
+};
+use log::info;
+use validator::Validate;
 
+use crate::db::repository::{self, SessionRepository, UserRepository};
 use crate::errors::ApiError;
-use crate::templates::dashboard as tp;
+use crate::handlers::user::extract_json_token;
+use crate::i18n::Locales;
+use crate::mails::{
+    self as mail,
+    user::{MailData, MailKind},
+};
+use crate::metrics::{outcome, LOGINS};
+use crate::middlewares::{csrf::CsrfToken, session::SessionCookie};
+use crate::templates::{dashboard as tp, Templates};
 
-pub async fn dashboard_login() -> Result<HttpResponse, ApiError> {
-    let html = tp::dashboard_login()?;
-    Ok(HttpResponse::Ok().body(html))
+#[derive(Debug, Deserialize)]
+pub struct LoginForm {
+    email: String,
+    password: String,
+    next: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginQuery {
+    next: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    search: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct EditUserForm {
+    #[validate(length(min = 1))]
+    username: String,
+    locale: String,
+}
+
+fn page(mut response: HttpResponseBuilder, html: String) -> HttpResponse {
+    response.content_type("text/html; charset=utf-8").body(html)
+}
+
+/// The dashboard page to show after the login, never another site
+fn next_page(next: Option<&str>) -> &str {
+    match next {
+        Some(next) if next.starts_with("/dashboard/") && !next.contains("//") => next,
+        _ => "/dashboard/users",
+    }
+}
+
+fn redirect(location: &str) -> HttpResponseBuilder {
+    let mut response = HttpResponse::SeeOther();
+    response.header(LOCATION, location);
+    response
+}
+
+pub async fn dashboard_login(
+    templates: web::Data<Templates>,
+    locales: web::Data<Locales>,
+    query: web::Query<LoginQuery>,
+    csrf: CsrfToken,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let html = tp::dashboard_login(
+        &templates,
+        &locales,
+        &locales.request_locale(&req),
+        &csrf.0,
+        next_page(query.next.as_deref()),
+        None,
+    )?;
+    Ok(page(HttpResponse::Ok(), html))
+}
+
+pub async fn dashboard_login_post(
+    sessions: web::Data<dyn SessionRepository>,
+    cookie: web::Data<SessionCookie>,
+    templates: web::Data<Templates>,
+    locales: web::Data<Locales>,
+    input: web::Form<LoginForm>,
+    csrf: CsrfToken,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let (email, password) = (input.email.clone(), input.password.clone());
+    let session = repository::run(&sessions, move |sessions| {
+        sessions.admin_auth(&email, &password)
+    })
+    .await;
+
+    LOGINS.inc(&[("source", "dashboard"), ("result", outcome(&session))]);
+    match session {
+        Ok(token) => Ok(redirect(next_page(input.next.as_deref()))
+            .cookie(cookie.build(token))
+            .finish()),
+        Err(e) => {
+            info!("Dashboard login refused for {} : {}", input.email, e);
+            let locale = locales.request_locale(&req);
+            let html = tp::dashboard_login(
+                &templates,
+                &locales,
+                &locale,
+                &csrf.0,
+                next_page(input.next.as_deref()),
+                Some(&locales.t(&locale, "dashboard-login-failed", &[])),
+            )?;
+            Ok(page(HttpResponse::Unauthorized(), html))
+        }
+    }
+}
+
+pub async fn users(
+    users: web::Data<dyn UserRepository>,
+    templates: web::Data<Templates>,
+    locales: web::Data<Locales>,
+    query: web::Query<SearchQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let search = query.search.as_deref().unwrap_or("").trim().to_owned();
+    let pattern = search.clone();
+    let users = repository::run(&users, move |users| users.search(&pattern)).await?;
+
+    let html = tp::dashboard_users(
+        &templates,
+        &locales,
+        &locales.request_locale(&req),
+        &search,
+        &users,
+    )?;
+    Ok(page(HttpResponse::Ok(), html))
+}
+
+pub async fn user(
+    users: web::Data<dyn UserRepository>,
+    templates: web::Data<Templates>,
+    locales: web::Data<Locales>,
+    id: web::Path<i32>,
+    csrf: CsrfToken,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let user = repository::run(&users, move |users| users.profile(id)).await?;
+
+    let html = tp::dashboard_user(
+        &templates,
+        &locales,
+        &locales.request_locale(&req),
+        &csrf.0,
+        &user,
+    )?;
+    Ok(page(HttpResponse::Ok(), html))
+}
+
+pub async fn edit_user(
+    users: web::Data<dyn UserRepository>,
+    templates: web::Data<Templates>,
+    locales: web::Data<Locales>,
+    id: web::Path<i32>,
+    csrf: CsrfToken,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let user = repository::run(&users, move |users| users.profile(id)).await?;
+
+    let html = tp::dashboard_user_edit(
+        &templates,
+        &locales,
+        &locales.request_locale(&req),
+        &csrf.0,
+        &user,
+    )?;
+    Ok(page(HttpResponse::Ok(), html))
+}
+
+pub async fn update_user(
+    users: web::Data<dyn UserRepository>,
+    locales: web::Data<Locales>,
+    id: web::Path<i32>,
+    input: web::Form<EditUserForm>,
+) -> Result<HttpResponse, ApiError> {
+    input.validate()?;
+    let id = id.into_inner();
+    let input = input.into_inner();
+    let locale = locales.resolve(&input.locale).to_owned();
+
+    repository::run(&users, move |users| {
+        users.update_profile(id, &input.username, &locale)
+    })
+    .await?;
+    Ok(redirect(&format!("/dashboard/users/{}", id)).finish())
+}
+
+/// promote, demote, activate, deactivate or impersonate a user
+pub async fn user_action(
+    users: web::Data<dyn UserRepository>,
+    sessions: web::Data<dyn SessionRepository>,
+    cookie: web::Data<SessionCookie>,
+    path: web::Path<(i32, String)>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let (id, action) = path.into_inner();
+    let admin = extract_json_token(req)?.id;
+    let done = action.clone();
+
+    // the user to impersonate
+    let impersonation = repository::run(&users, move |users| {
+        let user = users.get_by_id(id)?;
+        match action.as_ref() {
+            "demote" | "deactivate" if user.id == admin => {
+                return Err(ApiError::InternalError(
+                    "An admin can't demote or deactivate itself".to_owned(),
+                ))
+            }
+            "promote" => users.set_admin(user.id, true)?,
+            "demote" => users.set_admin(user.id, false)?,
+            "activate" => users.set_active(user.id, true)?,
+            "deactivate" => users.set_active(user.id, false)?,
+            "impersonate" => {
+                if user.is_admin || !user.is_active {
+                    return Err(ApiError::InternalError(
+                        "Only active users that are not admin can be impersonated".to_owned(),
+                    ));
+                }
+                info!("Admin {} impersonates user {}", admin, user.id);
+                return Ok(Some(user.id));
+            }
+            _ => {
+                return Err(ApiError::InternalError(format!(
+                    "Unknown action : {}",
+                    action
+                )))
+            }
+        }
+        Ok(None)
+    })
+    .await?;
+
+    if let Some(user) = impersonation {
+        let token = repository::run(&sessions, move |sessions| sessions.create_token(user)).await?;
+        return Ok(redirect("/").cookie(cookie.build(token)).finish());
+    }
+    info!("Admin {} : {} user {}", admin, done, id);
+    Ok(redirect(&format!("/dashboard/users/{}", id)).finish())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PreviewQuery {
+    /// html (default) or text
+    format: Option<String>,
+    locale: Option<String>,
+    #[serde(flatten)]
+    data: MailData,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct TestMail {
+    #[validate(email)]
+    to: String,
+    locale: Option<String>,
+    #[serde(flatten)]
+    data: MailData,
+}
+
+pub async fn mails() -> Result<HttpResponse, ApiError> {
+    let kinds: Vec<&str> = MailKind::ALL.iter().map(|kind| kind.name()).collect();
+    Ok(HttpResponse::Ok().json(kinds))
+}
+
+pub async fn preview_mail(
+    templates: web::Data<Templates>,
+    locales: web::Data<Locales>,
+    kind: web::Path<String>,
+    query: web::Query<PreviewQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let locale = match &query.locale {
+        Some(locale) => locale.to_owned(),
+        None => locales.request_locale(&req),
+    };
+    let kind = MailKind::from_name(&kind)?;
+    let email = mail::user::create_email(kind, &templates, &locales, &locale, &query.data)?;
+
+    match query.format.as_deref() {
+        Some("text") => {
+            let text = match email.text {
+                Some(text) => text,
+                None => mail::text::html_to_text(&email.content),
+            };
+            Ok(HttpResponse::Ok()
+                .content_type("text/plain; charset=utf-8")
+                .body(text))
+        }
+        // the preview runs on the dashboard origin, keep whatever got in the mail from running there
+        _ => Ok(HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .header(CONTENT_SECURITY_POLICY, "sandbox")
+            .body(email.browsable_html())),
+    }
+}
+
+pub async fn send_test_mail(
+    templates: web::Data<Templates>,
+    locales: web::Data<Locales>,
+    postman: web::Data<Addr<mail::Postman>>,
+    kind: web::Path<String>,
+    input: web::Json<TestMail>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    input.validate()?;
+    let locale = match &input.locale {
+        Some(locale) => locale.to_owned(),
+        None => locales.request_locale(&req),
+    };
+    let kind = MailKind::from_name(&kind)?;
+    let mut email = mail::user::create_email(kind, &templates, &locales, &locale, &input.data)?;
+    email.to = input.0.to;
+    email.title = format!("[TEST] {}", email.title);
+
+    mail::post_email(email, postman.get_ref())?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::memory::Memory;
+    use actix_web::{cookie::Cookie, http::StatusCode, test, App};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    #[actix_rt::test]
+    async fn users_are_managed_without_a_database() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let memory = Arc::new(Memory::default());
+        let admin = memory
+            .register(true, "Grace", "secret", "grace@example.com", "en")
+            .unwrap();
+        let user = memory
+            .register(false, "Ada", "secret", "ada@example.com", "fr")
+            .unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .configure(repository::configure(memory.clone()))
+                .data(SessionCookie::default())
+                .data(Templates::load(root.join("templates"), "Skeleton").unwrap())
+                .data(Locales::load(root.join("locales")).unwrap())
+                .route("/users", web::get().to(users))
+                .route("/users/{id}/{action}", web::post().to(user_action)),
+        )
+        .await;
+        let session = Cookie::new("__Host-BrancaToken", memory.create_token(admin).unwrap());
+        let action = |id: i32, action: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/users/{}/{}", id, action))
+                .cookie(session.clone())
+                .to_request()
+        };
+
+        let req = test::TestRequest::get()
+            .uri("/users?search=ADA")
+            .to_request();
+        let html = test::read_body(test::call_service(&mut app, req).await).await;
+        let html = String::from_utf8(html.to_vec()).unwrap();
+        assert!(html.contains("ada@example.com"));
+        assert!(!html.contains("grace@example.com"));
+
+        let res = test::call_service(&mut app, action(user, "promote")).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert!(memory.get_by_id(user).unwrap().is_admin);
+        let res = test::call_service(&mut app, action(user, "deactivate")).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert!(!memory.get_by_id(user).unwrap().is_active);
+
+        let res = test::call_service(&mut app, action(admin, "demote")).await;
+        assert!(res.status().is_server_error());
+        assert!(memory.get_by_id(admin).unwrap().is_admin);
+        let res = test::call_service(&mut app, action(user, "impersonate")).await;
+        assert!(res.status().is_server_error());
+    }
+
+    #[actix_rt::test]
+    async fn previews_escape_the_values_and_are_sandboxed() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let mut app = test::init_service(
+            App::new()
+                .data(Templates::load(root.join("templates"), "Skeleton").unwrap())
+                .data(Locales::load(root.join("locales")).unwrap())
+                .route("/mails/{kind}/preview", web::get().to(preview_mail)),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri(
+                "/mails/reset_token/preview?locale=en&username=%3Cscript%3Ealert(1)%3C%2Fscript%3E",
+            )
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(
+            res.headers().get(CONTENT_SECURITY_POLICY).unwrap(),
+            "sandbox"
+        );
+        let html = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+    }
 }
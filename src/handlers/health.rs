@@ -0,0 +1,97 @@
+use actix_web::{web, HttpResponse};
+use log::error;
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use crate::db;
+use crate::errors::ApiError;
+use crate::mails::transport;
+use crate::settings::Settings;
+use crate::templates::Templates;
+
+/// Outcome of a check. The probes are public, the errors only go to the logs.
+#[derive(Debug, Serialize)]
+pub struct Check {
+    status: &'static str,
+    latency_ms: f64,
+}
+
+impl Check {
+    fn new(name: &str, started: Instant, result: Result<(), ApiError>) -> Self {
+        let status = match result {
+            Ok(()) => "ok",
+            Err(e) => {
+                error!("Health check {} failed : {}", name, e);
+                "error"
+            }
+        };
+        Check {
+            status,
+            latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+        }
+    }
+
+    fn run<F: FnOnce() -> Result<(), ApiError>>(name: &str, check: F) -> Self {
+        let started = Instant::now();
+        Check::new(name, started, check())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    status: &'static str,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    checks: BTreeMap<&'static str, Check>,
+}
+
+/// The process is up and serving requests
+pub async fn live() -> HttpResponse {
+    HttpResponse::Ok().json(Health {
+        status: "ok",
+        checks: BTreeMap::new(),
+    })
+}
+
+/// Every dependency needed to serve requests works, 503 otherwise
+pub async fn ready(
+    pool: web::Data<db::DbPool>,
+    settings: web::Data<Settings>,
+    templates: web::Data<Templates>,
+) -> HttpResponse {
+    let mut checks = BTreeMap::new();
+
+    let started = Instant::now();
+    let database = db::run(&pool, db::ping).await;
+    checks.insert("database", Check::new("database", started, database));
+
+    let started = Instant::now();
+    let migrations = db::run(&pool, |conn| match db::migrations::pending(conn)? {
+        pending if pending.is_empty() => Ok(()),
+        pending => Err(ApiError::InternalError(format!(
+            "Pending migrations : {}",
+            pending.join(", ")
+        ))),
+    })
+    .await;
+    checks.insert("migrations", Check::new("migrations", started, migrations));
+
+    // a transport of its own, off the postman which may be busy with queued mails,
+    // and on the blocking pool as connecting resolves the host
+    let started = Instant::now();
+    let mail = web::block(move || transport::from_settings(&settings)?.check())
+        .await
+        .map_err(ApiError::from);
+    checks.insert("mail", Check::new("mail", started, mail));
+
+    checks.insert("templates", Check::run("templates", || templates.check()));
+
+    let healthy = checks.values().all(|check| check.status == "ok");
+    let health = Health {
+        status: if healthy { "ok" } else { "error" },
+        checks,
+    };
+    match healthy {
+        true => HttpResponse::Ok().json(health),
+        _ => HttpResponse::ServiceUnavailable().json(health),
+    }
+}
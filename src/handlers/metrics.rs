@@ -0,0 +1,16 @@
+use actix_web::{web, HttpResponse};
+
+use crate::db;
+use crate::metrics::{self, DB_POOL_CONNECTIONS, DB_POOL_IDLE};
+
+/// Every metric, in the Prometheus text format
+pub async fn metrics(pool: web::Data<db::DbPool>) -> HttpResponse {
+    // the pool state is only read when scraped
+    let state = pool.state();
+    DB_POOL_CONNECTIONS.set(&[], state.connections.into());
+    DB_POOL_IDLE.set(&[], state.idle_connections.into());
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(metrics::render())
+}
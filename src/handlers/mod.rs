@@ -1,2 +1,4 @@
 pub mod dashboard;
+pub mod health;
+pub mod metrics;
 pub mod user;
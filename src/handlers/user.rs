@@ -1,16 +1,19 @@
-use actix_web::{cookie::Cookie, web, HttpMessage, HttpRequest, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 use validator::Validate;
 
-use time::{Duration, OffsetDateTime};
-
-use crate::db;
+use crate::db::repository::{self, SessionRepository, TokenRepository, UserRepository};
 use crate::errors::ApiError;
+use crate::i18n::Locales;
 use crate::mails as mail;
+use crate::metrics::{outcome, LOGINS};
+use crate::middlewares::session::SessionCookie;
+use crate::templates::Templates;
+use crate::urls::{Flow, UrlBuilder};
 use actix::Addr;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct JsonBrancaToken {
-    id: i32,
+    pub id: i32,
     token: String,
 }
 
@@ -22,6 +25,7 @@ pub struct CreateUser {
     username: String,
     #[validate(length(min = 5))]
     password: String,
+    locale: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Validate)]
@@ -47,160 +51,503 @@ pub struct Mail {
 
 #[derive(Debug, Serialize, Deserialize, Validate)]
 pub struct ResetPassword {
-    #[validate(email)]
-    email: String,
+    user: i32,
     token: String,
+    expires: i64,
+    signature: String,
     password: String,
 }
 
 pub fn extract_json_token(req: HttpRequest) -> Result<JsonBrancaToken, ApiError> {
     let c = req
-        .cookie("BrancaToken")
-        .map(|cookie| cookie.value().to_string())
-        .ok_or(ApiError::InternalError("MissingToken".to_owned()))?;
+        .app_data::<web::Data<SessionCookie>>()
+        .map(|cookie| cookie.get_ref().clone())
+        .unwrap_or_default()
+        .value(&req)
+        .ok_or_else(|| ApiError::InternalError("MissingToken".to_owned()))?;
     let j: JsonBrancaToken = serde_json::from_str(&c)?;
     Ok(j)
 }
 
 pub async fn register(
-    pool: web::Data<db::DbPool>,
+    users: web::Data<dyn UserRepository>,
     postman: web::Data<Addr<mail::Postman>>,
+    templates: web::Data<Templates>,
+    locales: web::Data<Locales>,
     input: web::Json<CreateUser>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, ApiError> {
     input.validate()?;
-    let db = pool.get()?;
-
-    db::user::register(
-        false,
-        &input.0.username,
-        &input.0.password,
-        &input.0.email,
-        &db,
-    )?;
+    let input = input.into_inner();
+
+    let locale = match &input.locale {
+        Some(locale) => locales.resolve(locale).to_owned(),
+        None => locales.request_locale(&req),
+    };
+
+    let lang = locale.clone();
+    let input = repository::run(&users, move |users| {
+        users.register(false, &input.username, &input.password, &input.email, &lang)?;
+        Ok(input)
+    })
+    .await?;
 
     mail::post_email(
-        mail::user::create_register_email(input.0.email.as_ref(), input.0.username.as_ref())?,
+        mail::user::create_register_email(
+            &templates,
+            &locales,
+            &locale,
+            input.email.as_ref(),
+            input.username.as_ref(),
+        )?,
         postman.get_ref(),
     )?;
 
     Ok(HttpResponse::Ok().finish())
 }
 
+/// Replace the profile and password, the other sessions of the user end
 pub async fn update(
-    pool: web::Data<db::DbPool>,
+    users: web::Data<dyn UserRepository>,
+    sessions: web::Data<dyn SessionRepository>,
+    cookie: web::Data<SessionCookie>,
+    locales: web::Data<Locales>,
     input: web::Json<CreateUser>,
     req: HttpRequest,
 ) -> Result<HttpResponse, ApiError> {
     input.validate()?;
-    let db = pool.get()?;
     let j = extract_json_token(req)?;
+    let input = input.into_inner();
+    let locale = input
+        .locale
+        .as_deref()
+        .map(|locale| locales.resolve(locale).to_owned());
 
-    db::user::update(
-        &j.id,
-        &input.0.username,
-        &input.0.password,
-        &input.0.email,
-        &db,
-    )?;
+    let token = repository::run(&users, move |users| {
+        users.update(
+            j.id,
+            &input.username,
+            &input.password,
+            &input.email,
+            locale.as_deref(),
+        )?;
+        sessions.create_token(j.id)
+    })
+    .await?;
 
-    Ok(HttpResponse::Ok().finish())
+    Ok(HttpResponse::Ok().cookie(cookie.build(token)).finish())
 }
 
 pub async fn delete(
-    pool: web::Data<db::DbPool>,
+    users: web::Data<dyn UserRepository>,
     req: HttpRequest,
 ) -> Result<HttpResponse, ApiError> {
-    let db = pool.get()?;
     let j = extract_json_token(req)?;
-    db::user::delete(&j.id, &db)?;
+    repository::run(&users, move |users| users.delete(j.id)).await?;
     Ok(HttpResponse::Ok().finish())
 }
 
 pub async fn login(
-    pool: web::Data<db::DbPool>,
+    sessions: web::Data<dyn SessionRepository>,
+    cookie: web::Data<SessionCookie>,
     input: web::Json<AuthUser>,
 ) -> Result<HttpResponse, ApiError> {
     input.validate()?;
-    let db = pool.get()?;
+    let input = input.into_inner();
 
-    let result = db::user::auth(&input.0.email, &input.0.password, &db)?;
-    let c = Cookie::build("BrancaToken", result.to_owned())
-        //.domain("www.rust-lang.org")
-        .path("/")
-        //.secure(true)
-        .http_only(true)
-        .finish();
+    let result = repository::run(&sessions, move |sessions| {
+        sessions.auth(&input.email, &input.password)
+    })
+    .await;
+    LOGINS.inc(&[("source", "api"), ("result", outcome(&result))]);
+    Ok(HttpResponse::Ok().cookie(cookie.build(result?)).finish())
+}
 
-    Ok(HttpResponse::Ok().cookie(c).finish())
+pub async fn logout(cookie: web::Data<SessionCookie>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().cookie(cookie.removal()).finish())
 }
 
-pub async fn logout() -> Result<HttpResponse, ApiError> {
-    let c = Cookie::build("BrancaToken", "")
-        //.domain("www.rust-lang.org")
-        .path("/")
-        //.secure(true)
-        .http_only(true)
-        .max_age(Duration::zero())
-        .expires(OffsetDateTime::now_utc() - Duration::days(365))
-        .finish();
-    Ok(HttpResponse::Ok().cookie(c).finish())
+/// Issue a new token for the current session, restarting its lifetime
+pub async fn refresh(
+    sessions: web::Data<dyn SessionRepository>,
+    cookie: web::Data<SessionCookie>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let j = extract_json_token(req)?;
+    let token = repository::run(&sessions, move |sessions| sessions.create_token(j.id)).await?;
+    Ok(HttpResponse::Ok().cookie(cookie.build(token)).finish())
 }
 
-pub async fn get(pool: web::Data<db::DbPool>, req: HttpRequest) -> Result<HttpResponse, ApiError> {
-    let db = pool.get()?;
+pub async fn get(
+    users: web::Data<dyn UserRepository>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
     let j = extract_json_token(req)?;
-    let user = db::user::get_user_by_id(&j.id, &db)?;
+    let user = repository::run(&users, move |users| users.profile(j.id)).await?;
     Ok(HttpResponse::Ok().json(user))
 }
 
 pub async fn forgot_password(
-    pool: web::Data<db::DbPool>,
+    users: web::Data<dyn UserRepository>,
+    tokens: web::Data<dyn TokenRepository>,
     postman: web::Data<Addr<mail::Postman>>,
+    templates: web::Data<Templates>,
+    locales: web::Data<Locales>,
+    urls: web::Data<UrlBuilder>,
     input: web::Json<Mail>,
 ) -> Result<HttpResponse, ApiError> {
     input.validate()?;
-    let db = pool.get()?;
 
-    let user = db::user::get_user_by_email(&input.email, &db)?;
-    let token = db::user::generate_reset_token(&input.email, &db)?;
-    let mail = mail::user::create_reset_token_email(&input.email, &user.username, &token)?;
+    let email = input.email.clone();
+    let (user, token) = repository::run(&users, move |users| {
+        let user = users.get_by_email(&email)?;
+        let token = tokens.generate_reset_token(&email)?;
+        Ok((user, token))
+    })
+    .await?;
+    let url = urls.build(
+        Flow::ResetPassword,
+        &[("locale", &user.locale)],
+        &[("user", &user.id.to_string()), ("token", &token)],
+    )?;
+    let mail = mail::user::create_reset_token_email(
+        &templates,
+        &locales,
+        &user.locale,
+        &input.email,
+        &user.username,
+        &url,
+    )?;
     mail::post_email(mail, postman.get_ref())?;
 
     Ok(HttpResponse::Ok().finish())
 }
 
 pub async fn reset_password(
-    pool: web::Data<db::DbPool>,
+    users: web::Data<dyn UserRepository>,
+    tokens: web::Data<dyn TokenRepository>,
     postman: web::Data<Addr<mail::Postman>>,
+    templates: web::Data<Templates>,
+    locales: web::Data<Locales>,
+    urls: web::Data<UrlBuilder>,
     input: web::Json<ResetPassword>,
 ) -> Result<HttpResponse, ApiError> {
     input.validate()?;
-    let db = pool.get()?;
+    urls.verify(
+        Flow::ResetPassword,
+        &[("user", &input.user.to_string()), ("token", &input.token)],
+        input.expires,
+        &input.signature,
+    )?;
+    let input = input.into_inner();
 
-    let user = db::user::get_user_by_email(&input.email, &db)?;
-    db::user::verify_reset_token(&input.email, &input.token, &db)?;
-    db::user::change_password(&input.email, &input.password, &db)?;
-    let mail = mail::user::create_password_changed_success_email(&input.email, &user.username)?;
+    let user = repository::run(&users, move |users| {
+        let user = users.get_by_id(input.user)?;
+        tokens.verify_reset_token(&user.email, &input.token)?;
+        tokens.change_password(&user.email, &input.password)?;
+        Ok(user)
+    })
+    .await?;
+    let mail = mail::user::create_password_changed_success_email(
+        &templates,
+        &locales,
+        &user.locale,
+        &user.email,
+        &user.username,
+    )?;
     mail::post_email(mail, postman.get_ref())?;
 
     Ok(HttpResponse::Ok().finish())
 }
 
+/// The other sessions of the user end, this one gets a new token
+#[allow(clippy::too_many_arguments)] // an extractor per dependency
 pub async fn change_password(
-    pool: web::Data<db::DbPool>,
+    users: web::Data<dyn UserRepository>,
+    sessions: web::Data<dyn SessionRepository>,
+    tokens: web::Data<dyn TokenRepository>,
+    cookie: web::Data<SessionCookie>,
     postman: web::Data<Addr<mail::Postman>>,
+    templates: web::Data<Templates>,
+    locales: web::Data<Locales>,
     input: web::Json<ChangePassword>,
     req: HttpRequest,
 ) -> Result<HttpResponse, ApiError> {
     input.validate()?;
-    let db = pool.get()?;
+    let input = input.into_inner();
 
     let j = extract_json_token(req)?;
-    let user = db::user::get_user_by_id(&j.id, &db)?;
-    db::user::auth(&user.email, &input.old_password, &db)?;
-    db::user::change_password(&user.email, &input.new_password, &db)?;
-    let mail = mail::user::create_password_changed_success_email(&user.email, &user.username)?;
+    let (user, token) = repository::run(&users, move |users| {
+        let user = users.get_by_id(j.id)?;
+        sessions.auth(&user.email, &input.old_password)?;
+        tokens.change_password(&user.email, &input.new_password)?;
+        let token = sessions.create_token(user.id)?;
+        Ok((user, token))
+    })
+    .await?;
+    let mail = mail::user::create_password_changed_success_email(
+        &templates,
+        &locales,
+        &user.locale,
+        &user.email,
+        &user.username,
+    )?;
     mail::post_email(mail, postman.get_ref())?;
 
-    Ok(HttpResponse::Ok().finish())
+    Ok(HttpResponse::Ok().cookie(cookie.build(token)).finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::memory::Memory;
+    use crate::middlewares::session::{BrancaSession, Level};
+    use actix_web::{http::StatusCode, test, App};
+    use std::sync::Arc;
+
+    #[actix_rt::test]
+    async fn sessions_work_without_a_database() {
+        let memory = Arc::new(Memory::default());
+        let id = memory
+            .register(false, "Ada", "secret", "ada@example.com", "fr")
+            .unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .configure(repository::configure(memory.clone()))
+                .data(SessionCookie::default())
+                .service(
+                    web::scope("/api/v1")
+                        .wrap(BrancaSession(Level::User))
+                        .route("/login", web::post().to(login))
+                        .route("/user", web::get().to(get))
+                        .route("/user/delete", web::delete().to(delete)),
+                ),
+        )
+        .await;
+
+        let login = |password: &str| {
+            test::TestRequest::post()
+                .uri("/api/v1/login")
+                .set_json(&serde_json::json!({ "email": "ada@example.com", "password": password }))
+                .to_request()
+        };
+        let res = test::call_service(&mut app, login("wrong")).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let res = test::call_service(&mut app, login("secret")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let cookie = res.response().cookies().next().unwrap().into_owned();
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/user")
+            .cookie(cookie.clone())
+            .to_request();
+        let user: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(user["id"], id);
+        assert_eq!(user["locale"], "fr");
+
+        let req = test::TestRequest::delete()
+            .uri("/api/v1/user/delete")
+            .cookie(cookie.clone())
+            .to_request();
+        assert_eq!(
+            test::call_service(&mut app, req).await.status(),
+            StatusCode::OK
+        );
+        assert!(memory.get_by_id(id).is_err());
+
+        // the session of a deleted user is over
+        let req = test::TestRequest::get()
+            .uri("/api/v1/user")
+            .cookie(cookie)
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+}
+
+/// Every flow of the api, through the real app on a test database
+#[cfg(test)]
+mod flows {
+    use crate::testing::{self, link};
+    use actix_web::{http::StatusCode, test::TestRequest};
+    use serde_json::{json, Value};
+
+    const PUBLIC_URL: &str = "https://skeleton.test";
+
+    #[actix_rt::test]
+    #[cfg_attr(feature = "postgres", ignore = "needs TEST_DATABASE_URL")]
+    async fn registration_and_login() {
+        let mut app = testing::start().await;
+        assert_eq!(
+            app.register("Ada", "ada@flows.test", "secret").await,
+            StatusCode::OK
+        );
+        let mails = app.mails().await;
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].to, ["ada@flows.test"]);
+        assert!(mails[0].message.contains("Welcome on Skeleton"));
+
+        // the email is taken, and the input is validated
+        assert!(app
+            .register("Ada", "ada@flows.test", "secret")
+            .await
+            .is_server_error());
+        assert!(app
+            .register("Bob", "not an email", "secret")
+            .await
+            .is_server_error());
+        assert!(app
+            .register("Bob", "bob@flows.test", "abc")
+            .await
+            .is_server_error());
+        assert_eq!(app.mails().await.len(), 1);
+
+        let res = app.call(TestRequest::get().uri("/api/v1/user")).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        assert!(app.login("ada@flows.test", "wrong").await.is_server_error());
+        assert!(!app.logged_in());
+        assert_eq!(app.login("ada@flows.test", "secret").await, StatusCode::OK);
+        assert!(app.logged_in());
+
+        let (status, user): (_, Value) = app.json(TestRequest::get().uri("/api/v1/user")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(user["username"], "Ada");
+        assert_eq!(user["locale"], "en");
+        assert!(user.get("email").is_none() && user.get("password_hash").is_none());
+
+        assert_eq!(app.post("/api/v1/refresh", json!({})).await, StatusCode::OK);
+        let res = app.call(TestRequest::get().uri("/api/v1/user")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    #[cfg_attr(feature = "postgres", ignore = "needs TEST_DATABASE_URL")]
+    async fn profile_update_logout_and_deletion() {
+        let mut app = testing::start().await;
+        app.register("Grace", "grace@flows.test", "secret").await;
+        app.login("grace@flows.test", "secret").await;
+
+        let update = json!({
+            "username": "Grace H",
+            "email": "hopper@flows.test",
+            "password": "cobol",
+            "locale": "fr"
+        });
+        let req = TestRequest::put()
+            .uri("/api/v1/user/update")
+            .set_json(&update);
+        assert_eq!(app.call(req).await.status(), StatusCode::OK);
+        let (_, user): (_, Value) = app.json(TestRequest::get().uri("/api/v1/user")).await;
+        assert_eq!(user["username"], "Grace H");
+        assert_eq!(user["locale"], "fr");
+
+        let res = app.call(TestRequest::get().uri("/api/v1/logout")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!app.logged_in());
+        let res = app.call(TestRequest::get().uri("/api/v1/user")).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        assert!(app
+            .login("grace@flows.test", "secret")
+            .await
+            .is_server_error());
+        assert_eq!(
+            app.login("hopper@flows.test", "cobol").await,
+            StatusCode::OK
+        );
+
+        let req = TestRequest::delete().uri("/api/v1/user/delete");
+        assert_eq!(app.call(req).await.status(), StatusCode::OK);
+        // the cookie remains, but its user is gone
+        assert!(app.logged_in());
+        let res = app.call(TestRequest::get().uri("/api/v1/user")).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(app
+            .login("hopper@flows.test", "cobol")
+            .await
+            .is_server_error());
+    }
+
+    #[actix_rt::test]
+    #[cfg_attr(feature = "postgres", ignore = "needs TEST_DATABASE_URL")]
+    async fn passwords_change_and_reset() {
+        let mut app = testing::start().await;
+        app.register("Linus", "linus@flows.test", "secret").await;
+        app.login("linus@flows.test", "secret").await;
+
+        let change = |old: &str, new: &str| {
+            TestRequest::post()
+                .uri("/api/v1/user/change_password")
+                .set_json(&json!({ "old_password": old, "new_password": new }))
+        };
+        assert!(app
+            .call(change("wrong", "kernel"))
+            .await
+            .status()
+            .is_server_error());
+        let before = app.session().unwrap();
+        assert_eq!(
+            app.call(change("secret", "kernel")).await.status(),
+            StatusCode::OK
+        );
+        // this session goes on with a new cookie, the other ones end
+        let after = app.session().unwrap();
+        assert_ne!(after.value(), before.value());
+        let res = app.call(TestRequest::get().uri("/api/v1/user")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        app.set_session(before);
+        let res = app.call(TestRequest::get().uri("/api/v1/user")).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        app.set_session(after);
+
+        let mails = app.mails().await;
+        assert_eq!(mails.len(), 2);
+        assert!(mails[1].message.contains("Password Reset Success"));
+        assert_eq!(
+            app.login("linus@flows.test", "kernel").await,
+            StatusCode::OK
+        );
+
+        // an unknown email gets no mail
+        let forgot = |email: &str| json!({ "email": email });
+        assert!(app
+            .post("/api/v1/user/forgot_password", forgot("nobody@flows.test"))
+            .await
+            .is_server_error());
+        assert_eq!(
+            app.post("/api/v1/user/forgot_password", forgot("linus@flows.test"))
+                .await,
+            StatusCode::OK
+        );
+        let mails = app.mails().await;
+        assert_eq!(mails.len(), 3);
+        assert!(mails[2].message.contains("Password Reset"));
+        let url = link(&mails[2], PUBLIC_URL).expect("a reset link");
+        let mut reset: serde_json::Map<String, Value> = url
+            .query_pairs()
+            .map(|(key, value)| (key.into_owned(), Value::String(value.into_owned())))
+            .collect();
+        reset["user"] = json!(reset["user"].as_str().unwrap().parse::<i32>().unwrap());
+        reset["expires"] = json!(reset["expires"].as_str().unwrap().parse::<i64>().unwrap());
+        reset.insert("password".to_owned(), json!("unix!"));
+
+        let mut forged = reset.clone();
+        forged["token"] = json!("000000");
+        assert!(app
+            .post("/api/v1/user/reset_password", Value::Object(forged))
+            .await
+            .is_server_error());
+        assert_eq!(
+            app.post("/api/v1/user/reset_password", Value::Object(reset))
+                .await,
+            StatusCode::OK
+        );
+        assert_eq!(app.mails().await.len(), 4);
+        assert!(app
+            .login("linus@flows.test", "kernel")
+            .await
+            .is_server_error());
+        assert_eq!(app.login("linus@flows.test", "unix!").await, StatusCode::OK);
+    }
 }
@@ -0,0 +1,213 @@
+use actix_web::{http::header::ACCEPT_LANGUAGE, HttpRequest};
+
+use fluent_bundle::concurrent::FluentBundle;
+use fluent_bundle::{FluentArgs, FluentResource};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::errors::ApiError;
+use crate::settings::Settings;
+
+/// The locale used when nothing better matches, its catalog must exist
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// Message catalogs, one per locale, loaded from the Fluent `<locale>.ftl` files
+#[derive(Clone)]
+pub struct Locales {
+    catalogs: Arc<HashMap<String, FluentBundle<FluentResource>>>,
+}
+
+impl Locales {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ApiError> {
+        let mut catalogs = HashMap::new();
+        for entry in fs::read_dir(path.as_ref())? {
+            let file = entry?.path();
+            if file.extension().and_then(|e| e.to_str()) != Some("ftl") {
+                continue;
+            }
+            let locale = file
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or_default()
+                .to_lowercase();
+            let catalog = parse_catalog(&locale, fs::read_to_string(&file)?)
+                .map_err(|e| ApiError::InternalError(format!("{} : {}", file.display(), e)))?;
+            catalogs.insert(locale, catalog);
+        }
+
+        if !catalogs.contains_key(DEFAULT_LOCALE) {
+            return Err(ApiError::InternalError(format!(
+                "Missing catalog for the default locale : {}",
+                DEFAULT_LOCALE
+            )));
+        }
+        info!("{} locales loaded", catalogs.len());
+
+        Ok(Locales {
+            catalogs: Arc::new(catalogs),
+        })
+    }
+
+    /// The supported locale closest to the requested one, `fr-CH` falling back to `fr`
+    pub fn resolve(&self, locale: &str) -> &str {
+        let locale = locale.trim().to_lowercase();
+        let language = locale.split(['-', '_']).next().unwrap_or("");
+        self.catalogs
+            .get_key_value(locale.as_str())
+            .or_else(|| self.catalogs.get_key_value(language))
+            .map(|(key, _)| key.as_str())
+            .unwrap_or(DEFAULT_LOCALE)
+    }
+
+    /// Every supported locale, sorted
+    pub fn available(&self) -> Vec<&str> {
+        let mut locales: Vec<&str> = self.catalogs.keys().map(|key| key.as_str()).collect();
+        locales.sort_unstable();
+        locales
+    }
+
+    /// Best supported locale of an `Accept-Language` header, by quality
+    pub fn negotiate(&self, accept_language: &str) -> &str {
+        let mut ranges: Vec<(&str, f32)> = accept_language
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next()?.trim();
+                let quality = parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                Some((tag, quality))
+            })
+            .filter(|(tag, quality)| !tag.is_empty() && *tag != "*" && *quality > 0.0)
+            .collect();
+        // stable sort, so equal qualities keep the header order
+        ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+        for (tag, _) in ranges {
+            let resolved = self.resolve(tag);
+            if resolved != DEFAULT_LOCALE || tag.to_lowercase().starts_with(DEFAULT_LOCALE) {
+                return resolved;
+            }
+        }
+        DEFAULT_LOCALE
+    }
+
+    /// Locale of an anonymous request, from its `Accept-Language` header
+    pub fn request_locale(&self, req: &HttpRequest) -> String {
+        req.headers()
+            .get(ACCEPT_LANGUAGE)
+            .and_then(|h| h.to_str().ok())
+            .map(|h| self.negotiate(h))
+            .unwrap_or(DEFAULT_LOCALE)
+            .to_owned()
+    }
+
+    /// Translate `key`, falling back to the default locale then to the key itself
+    pub fn t(&self, locale: &str, key: &str, args: &[(&str, &str)]) -> String {
+        let found = [self.resolve(locale), DEFAULT_LOCALE]
+            .iter()
+            .filter_map(|locale| self.catalogs.get(*locale))
+            .find_map(|catalog| Some((catalog, catalog.get_message(key)?.value()?)));
+        let (catalog, pattern) = match found {
+            Some(found) => found,
+            None => return key.to_owned(),
+        };
+
+        let mut fluent_args = FluentArgs::new();
+        for (name, value) in args {
+            fluent_args.set(*name, *value);
+        }
+        let mut errors = vec![];
+        let message = catalog.format_pattern(pattern, Some(&fluent_args), &mut errors);
+        if !errors.is_empty() {
+            warn!("Message {} of {} : {:?}", key, locale, errors);
+        }
+        message.into_owned()
+    }
+}
+
+/// A bundle of the whole file, refused on the first syntax error rather than missing messages
+fn parse_catalog(locale: &str, source: String) -> Result<FluentBundle<FluentResource>, String> {
+    let language = locale
+        .parse()
+        .map_err(|e| format!("invalid locale {} : {}", locale, e))?;
+    let resource =
+        FluentResource::try_new(source).map_err(|(_, errors)| format!("{:?}", errors))?;
+
+    let mut bundle = FluentBundle::new_concurrent(vec![language]);
+    // no Unicode isolation marks around the values, mails and pages are not bidirectional
+    bundle.set_use_isolating(false);
+    bundle
+        .add_resource(resource)
+        .map_err(|errors| format!("{:?}", errors))?;
+    Ok(bundle)
+}
+
+/// Load the catalogs of `locales_path`
+pub fn from_settings(settings: &Settings) -> Result<Locales, ApiError> {
+    Locales::load(&settings.locales_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locales() -> Locales {
+        Locales::load(Path::new(env!("CARGO_MANIFEST_DIR")).join("locales")).unwrap()
+    }
+
+    #[test]
+    fn accept_language_is_negotiated() {
+        let locales = locales();
+        assert_eq!(locales.negotiate("fr-CH, fr;q=0.9, en;q=0.8"), "fr");
+        assert_eq!(locales.negotiate("es, de;q=0.5"), "de");
+        assert_eq!(locales.negotiate("en;q=0.1, de;q=0.5"), "de");
+        assert_eq!(locales.negotiate("es, *;q=0.5"), "en");
+        assert_eq!(locales.negotiate(""), "en");
+    }
+
+    #[test]
+    fn messages_fall_back_to_default_locale() {
+        let locales = locales();
+        assert_eq!(
+            locales.t("de-AT", "register-header", &[("username", "Hans")]),
+            "Willkommen, Hans"
+        );
+        assert_eq!(
+            locales.t("it", "register-header", &[("username", "Marco")]),
+            "Welcome, Marco"
+        );
+        assert_eq!(locales.t("fr", "unknown-key", &[]), "unknown-key");
+    }
+
+    #[test]
+    fn catalogs_are_fluent() {
+        let path = std::env::temp_dir().join(format!("skeleton-locales-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&path).unwrap();
+        fs::write(
+            path.join("en.ftl"),
+            "-brand = Skeleton\n\
+             welcome =\n    Welcome on { -brand },\n    { $username }\n\
+             role = { $role ->\n    [admin] Administrator\n   *[other] User\n}\n",
+        )
+        .unwrap();
+        let locales = Locales::load(&path).unwrap();
+        assert_eq!(
+            locales.t("en", "welcome", &[("username", "Hans")]),
+            "Welcome on Skeleton,\nHans"
+        );
+        assert_eq!(
+            locales.t("en", "role", &[("role", "admin")]),
+            "Administrator"
+        );
+        assert_eq!(locales.t("en", "role", &[("role", "")]), "User");
+
+        fs::write(path.join("en.ftl"), "welcome = { $username\n").unwrap();
+        assert!(Locales::load(&path).is_err());
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,543 @@
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+use tracing_core::span::Current;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::errors::ApiError;
+use crate::settings::Settings;
+use crate::telemetry::{self, FinishedSpan, SpanExporter};
+
+/// Fields never written as is, matched anywhere in the field name
+const SENSITIVE: &[&str] = &[
+    "password",
+    "pwd",
+    "token",
+    "secret",
+    "authorization",
+    "cookie",
+    "credential",
+    "key",
+];
+const REDACTED: &str = "[redacted]";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Text,
+}
+
+impl std::str::FromStr for Format {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "json" => Ok(Format::Json),
+            "text" => Ok(Format::Text),
+            _ => Err(ApiError::InternalError(format!(
+                "Unknown log format : {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Levels by target, `info,actix_server=warn,skeleton_api::db=debug` like `RUST_LOG`
+#[derive(Debug, Clone)]
+pub struct Filter {
+    default: log::LevelFilter,
+    targets: Vec<(String, log::LevelFilter)>,
+}
+
+impl std::str::FromStr for Filter {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ApiError::InternalError(format!("Invalid log level : {}", s));
+        let mut filter = Filter {
+            default: log::LevelFilter::Info,
+            targets: Vec::new(),
+        };
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => filter
+                    .targets
+                    .push((target.to_owned(), level.parse().map_err(|_| invalid())?)),
+                None => filter.default = directive.parse().map_err(|_| invalid())?,
+            }
+        }
+        // the most specific target wins
+        filter
+            .targets
+            .sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+        Ok(filter)
+    }
+}
+
+impl Filter {
+    fn enabled(&self, target: &str, level: log::Level) -> bool {
+        let max = self
+            .targets
+            .iter()
+            .find(|(prefix, _)| target.starts_with(prefix.as_str()))
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default);
+        level <= max
+    }
+
+    fn max(&self) -> log::LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, std::cmp::max)
+    }
+}
+
+fn level(level: &tracing::Level) -> log::Level {
+    match *level {
+        tracing::Level::ERROR => log::Level::Error,
+        tracing::Level::WARN => log::Level::Warn,
+        tracing::Level::INFO => log::Level::Info,
+        tracing::Level::DEBUG => log::Level::Debug,
+        tracing::Level::TRACE => log::Level::Trace,
+    }
+}
+
+fn is_sensitive(name: &str) -> bool {
+    let name = name.to_lowercase();
+    SENSITIVE.iter().any(|sensitive| name.contains(sensitive))
+}
+
+/// Collects the fields of a span or an event, redacting the sensitive ones
+struct Fields<'a>(&'a mut Map<String, Value>);
+
+impl Fields<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        let value = match is_sensitive(field.name()) {
+            true => Value::from(REDACTED),
+            _ => value,
+        };
+        self.0.insert(field.name().to_owned(), value);
+    }
+}
+
+impl Visit for Fields<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{:?}", value).into());
+    }
+}
+
+struct SpanData {
+    metadata: &'static Metadata<'static>,
+    parent: Option<Id>,
+    fields: Map<String, Value>,
+    refs: usize,
+    trace_id: u128,
+    span_id: u64,
+    /// the parent span, possibly in another service when given by a `traceparent` field
+    parent_span_id: Option<u64>,
+    start: SystemTime,
+}
+
+thread_local! {
+    /// Spans entered on this thread, the innermost last
+    static STACK: RefCell<Vec<Id>> = const { RefCell::new(Vec::new()) };
+}
+
+struct Inner {
+    format: Format,
+    filter: Filter,
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<Id, SpanData>>,
+    output: Mutex<Box<dyn Write + Send>>,
+    exporter: Option<Arc<dyn SpanExporter>>,
+}
+
+/// Writes the `log` records and `tracing` events, one line each, with the fields
+/// of the spans they happen in (request id, route, user id...).
+///
+/// Spans also carry a trace context, continued from a `traceparent` field,
+/// and are handed to the exporter once closed.
+#[derive(Clone)]
+pub struct Logger(Arc<Inner>);
+
+impl Logger {
+    pub fn new(
+        format: Format,
+        filter: Filter,
+        output: Box<dyn Write + Send>,
+        exporter: Option<Arc<dyn SpanExporter>>,
+    ) -> Self {
+        Logger(Arc::new(Inner {
+            format,
+            filter,
+            next_id: AtomicU64::new(1),
+            spans: Mutex::new(HashMap::new()),
+            output: Mutex::new(output),
+            exporter,
+        }))
+    }
+
+    fn export(&self, data: SpanData) {
+        let exporter = match &self.0.exporter {
+            Some(exporter) => exporter,
+            None => return,
+        };
+        let mut attributes = data.fields;
+        let kind = match attributes.remove("otel.kind") {
+            Some(Value::String(kind)) => kind,
+            _ => "internal".to_owned(),
+        };
+        let error = matches!(attributes.remove("otel.status_code"), Some(Value::String(code)) if code == "ERROR");
+        exporter.export(FinishedSpan {
+            name: data.metadata.name(),
+            trace_id: data.trace_id,
+            span_id: data.span_id,
+            parent_span_id: data.parent_span_id,
+            kind,
+            start: data.start,
+            end: SystemTime::now(),
+            attributes,
+            error,
+        });
+    }
+
+    fn current(&self) -> Option<Id> {
+        STACK.with(|stack| stack.borrow().last().cloned())
+    }
+
+    /// Fields of the span and its parents, the innermost winning
+    fn context(&self, span: Option<Id>) -> (Option<&'static str>, Map<String, Value>) {
+        let spans = self.0.spans.lock().unwrap_or_else(|e| e.into_inner());
+        let mut chain = Vec::new();
+        let mut next = span;
+        while let Some(data) = next.and_then(|id| spans.get(&id)) {
+            chain.push(data);
+            next = data.parent.clone();
+        }
+
+        let mut fields = Map::new();
+        for data in chain.iter().rev() {
+            fields.extend(data.fields.clone());
+        }
+        (chain.first().map(|data| data.metadata.name()), fields)
+    }
+
+    fn write(&self, level: log::Level, target: &str, message: String, fields: Map<String, Value>) {
+        let (span, mut context) = self.context(self.current());
+        context.extend(fields);
+        let timestamp = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+
+        let line = match self.0.format {
+            Format::Json => {
+                let mut line = Map::new();
+                line.insert("timestamp".to_owned(), timestamp.into());
+                line.insert("level".to_owned(), level.as_str().into());
+                line.insert("target".to_owned(), target.into());
+                line.insert("message".to_owned(), message.into());
+                if let Some(span) = span {
+                    line.insert("span".to_owned(), span.into());
+                }
+                for (name, value) in context {
+                    line.entry(name).or_insert(value);
+                }
+                Value::Object(line).to_string()
+            }
+            Format::Text => {
+                let mut line = format!("{} {:<5} {}: {}", timestamp, level, target, message);
+                for (name, value) in context {
+                    line.push_str(&format!(" {}={}", name, value));
+                }
+                line
+            }
+        };
+
+        let mut output = self.0.output.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = writeln!(output, "{}", line);
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.filter.enabled(metadata.target(), metadata.level())
+    }
+
+    fn log(&self, record: &log::Record) {
+        if log::Log::enabled(self, record.metadata()) {
+            self.write(
+                record.level(),
+                record.target(),
+                record.args().to_string(),
+                Map::new(),
+            );
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self
+            .0
+            .output
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .flush();
+    }
+}
+
+impl Subscriber for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.0
+            .filter
+            .enabled(metadata.target(), level(metadata.level()))
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+        let id = Id::from_u64(self.0.next_id.fetch_add(1, Ordering::Relaxed));
+        let parent = match attributes.parent() {
+            Some(parent) => Some(parent.clone()),
+            None if attributes.is_contextual() => self.current(),
+            None => None,
+        };
+        let mut fields = Map::new();
+        attributes.record(&mut Fields(&mut fields));
+        let remote = match fields.remove("traceparent") {
+            Some(Value::String(header)) => telemetry::parse_traceparent(&header),
+            _ => None,
+        };
+
+        let mut spans = self.0.spans.lock().unwrap_or_else(|e| e.into_inner());
+        // a parent lives as long as its children, even once its handle is dropped
+        let parent = parent.filter(|parent| match spans.get_mut(parent) {
+            Some(data) => {
+                data.refs += 1;
+                true
+            }
+            None => false,
+        });
+        let (trace_id, parent_span_id) = match parent.as_ref().and_then(|p| spans.get(p)) {
+            Some(data) => (data.trace_id, Some(data.span_id)),
+            None => match remote {
+                Some(remote) => (remote.trace_id, Some(remote.span_id)),
+                None => (rand::random::<u128>().max(1), None),
+            },
+        };
+        spans.insert(
+            id.clone(),
+            SpanData {
+                metadata: attributes.metadata(),
+                parent,
+                fields,
+                refs: 1,
+                trace_id,
+                span_id: rand::random::<u64>().max(1),
+                parent_span_id,
+                start: SystemTime::now(),
+            },
+        );
+        id
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut spans = self.0.spans.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(data) = spans.get_mut(span) {
+            values.record(&mut Fields(&mut data.fields));
+        }
+    }
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Map::new();
+        event.record(&mut Fields(&mut fields));
+        let message = match fields.remove("message") {
+            Some(Value::String(message)) => message,
+            Some(message) => message.to_string(),
+            None => String::new(),
+        };
+        let metadata = event.metadata();
+        self.write(level(metadata.level()), metadata.target(), message, fields);
+    }
+
+    fn enter(&self, span: &Id) {
+        STACK.with(|stack| stack.borrow_mut().push(span.clone()));
+    }
+
+    fn exit(&self, span: &Id) {
+        STACK.with(|stack| {
+            let mut stack = stack.borrow_mut();
+            if let Some(position) = stack.iter().rposition(|id| id == span) {
+                stack.remove(position);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        let mut spans = self.0.spans.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(data) = spans.get_mut(span) {
+            data.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.0.spans.lock().unwrap_or_else(|e| e.into_inner());
+        let mut next = Some(span.clone());
+        while let Some(id) = next.take() {
+            let closed = match spans.get_mut(&id) {
+                Some(data) => {
+                    data.refs -= 1;
+                    data.refs == 0
+                }
+                None => false,
+            };
+            if let Some(data) = closed.then(|| spans.remove(&id)).flatten() {
+                next = data.parent.clone();
+                self.export(data);
+            }
+        }
+        !spans.contains_key(&span)
+    }
+
+    fn current_span(&self) -> Current {
+        let spans = self.0.spans.lock().unwrap_or_else(|e| e.into_inner());
+        match self
+            .current()
+            .and_then(|id| spans.get(&id).map(|data| (id, data.metadata)))
+        {
+            Some((id, metadata)) => Current::new(id, metadata),
+            None => Current::none(),
+        }
+    }
+}
+
+/// Send the `log` records and `tracing` events to the standard error,
+/// in the `log_format` format and filtered by `log_level`, and the spans to `exporter`
+pub fn from_settings(
+    settings: &Settings,
+    exporter: Option<Arc<dyn SpanExporter>>,
+) -> Result<(), ApiError> {
+    let filter: Filter = settings.log_level.parse()?;
+    let logger = Logger::new(
+        settings.log_format.parse()?,
+        filter.clone(),
+        Box::new(io::stderr()),
+        exporter,
+    );
+
+    log::set_logger(Box::leak(Box::new(logger.clone())))
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    log::set_max_level(filter.max());
+    tracing::subscriber::set_global_default(logger)
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lines written by the logger, readable by the test
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn events_carry_span_fields_without_secrets() {
+        let output = Captured::default();
+        let logger = Logger::new(
+            Format::Json,
+            "info,noisy=error".parse().unwrap(),
+            Box::new(output.clone()),
+            None,
+        );
+
+        tracing::subscriber::with_default(logger.clone(), || {
+            let request = tracing::info_span!(
+                "request",
+                request_id = "abc",
+                user_id = tracing::field::Empty
+            );
+            let _request = request.enter();
+            request.record("user_id", 42);
+
+            let mail = tracing::info_span!("mail", to = "user@skeleton.test");
+            drop(_request);
+            drop(request);
+            let _mail = mail.enter();
+            tracing::info!(password = "hunter2", session_token = "t0k3n", "mail sent");
+            tracing::debug!("filtered out");
+            log::Log::log(
+                &logger,
+                &log::Record::builder()
+                    .level(log::Level::Info)
+                    .target("skeleton")
+                    .args(format_args!("from log"))
+                    .build(),
+            );
+            log::Log::log(
+                &logger,
+                &log::Record::builder()
+                    .level(log::Level::Warn)
+                    .target("noisy::module")
+                    .args(format_args!("too noisy"))
+                    .build(),
+            );
+        });
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+
+        assert_eq!(lines[0]["message"], "mail sent");
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["span"], "mail");
+        assert_eq!(lines[0]["request_id"], "abc");
+        assert_eq!(lines[0]["user_id"], 42);
+        assert_eq!(lines[0]["to"], "user@skeleton.test");
+        assert_eq!(lines[0]["password"], REDACTED);
+        assert_eq!(lines[0]["session_token"], REDACTED);
+        assert!(!output.contains("hunter2") && !output.contains("t0k3n"));
+
+        assert_eq!(lines[1]["message"], "from log");
+        assert_eq!(lines[1]["request_id"], "abc");
+    }
+}
@@ -0,0 +1,344 @@
+use ring::digest::{digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, RsaKeyPair, RSA_PKCS1_SHA256};
+
+use chrono::Utc;
+use std::fs;
+
+use crate::errors::ApiError;
+use crate::settings::Settings;
+
+pub const DEFAULT_HEADERS: &str =
+    "from:to:cc:reply-to:subject:date:message-id:mime-version:content-type";
+
+/// A private key able to produce DKIM signatures
+pub enum DkimKey {
+    Rsa(RsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+impl DkimKey {
+    /// Load a key from a PEM (PKCS#8 or PKCS#1 for RSA) or DER (PKCS#8) file.
+    /// `algorithm` is either "rsa" or "ed25519".
+    pub fn from_file(path: &str, algorithm: &str) -> Result<Self, ApiError> {
+        let raw = fs::read(path)?;
+        let (der, pkcs1) = match std::str::from_utf8(&raw) {
+            Ok(pem) if pem.contains("-----BEGIN") => (
+                base64::decode(
+                    pem.lines()
+                        .filter(|line| !line.starts_with("-----"))
+                        .collect::<String>(),
+                )
+                .map_err(|e| ApiError::InternalError(format!("Invalid DKIM key : {}", e)))?,
+                pem.contains("BEGIN RSA PRIVATE KEY"),
+            ),
+            _ => (raw, false),
+        };
+
+        let invalid = |e: ring::error::KeyRejected| {
+            ApiError::InternalError(format!("Invalid DKIM key : {}", e))
+        };
+        match algorithm.to_lowercase().as_ref() {
+            "rsa" if pkcs1 => Ok(DkimKey::Rsa(RsaKeyPair::from_der(&der).map_err(invalid)?)),
+            "rsa" => Ok(DkimKey::Rsa(RsaKeyPair::from_pkcs8(&der).map_err(invalid)?)),
+            "ed25519" => Ok(DkimKey::Ed25519(
+                Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der).map_err(invalid)?,
+            )),
+            _ => Err(ApiError::InternalError(format!(
+                "Unknown DKIM algorithm : {}",
+                algorithm
+            ))),
+        }
+    }
+
+    fn algorithm(&self) -> &'static str {
+        match self {
+            DkimKey::Rsa(_) => "rsa-sha256",
+            DkimKey::Ed25519(_) => "ed25519-sha256",
+        }
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>, ApiError> {
+        match self {
+            DkimKey::Rsa(key) => {
+                let mut signature = vec![0; key.public_modulus_len()];
+                key.sign(
+                    &RSA_PKCS1_SHA256,
+                    &SystemRandom::new(),
+                    data,
+                    &mut signature,
+                )
+                .map_err(|_| ApiError::InternalError("DKIM signature failed".to_owned()))?;
+                Ok(signature)
+            }
+            // RFC 8463 : ed25519 signs the sha256 hash of the data
+            DkimKey::Ed25519(key) => Ok(key.sign(digest(&SHA256, data).as_ref()).as_ref().to_vec()),
+        }
+    }
+}
+
+/// Signs outgoing messages with a `DKIM-Signature` header,
+/// using relaxed canonicalization for both headers and body.
+pub struct DkimSigner {
+    domain: String,
+    selector: String,
+    headers: Vec<String>,
+    key: DkimKey,
+}
+
+impl DkimSigner {
+    pub fn new(domain: &str, selector: &str, headers: &str, key: DkimKey) -> Self {
+        DkimSigner {
+            domain: domain.to_owned(),
+            selector: selector.to_owned(),
+            headers: headers
+                .split(':')
+                .map(|name| name.trim().to_lowercase())
+                .filter(|name| !name.is_empty())
+                .collect(),
+            key,
+        }
+    }
+
+    /// Return the message with its `DKIM-Signature` header prepended
+    pub fn sign(&self, message: &str) -> Result<String, ApiError> {
+        self.sign_at(message, Utc::now().timestamp())
+    }
+
+    /// `sign`, with `timestamp` as the signing time
+    fn sign_at(&self, message: &str, timestamp: i64) -> Result<String, ApiError> {
+        let (header_block, body) = split_message(message);
+        let fields = header_fields(header_block);
+
+        // only sign the configured headers that are really present
+        let signed: Vec<&(String, String)> = self
+            .headers
+            .iter()
+            .filter_map(|name| fields.iter().rev().find(|(n, _)| n.to_lowercase() == *name))
+            .collect();
+
+        let body_hash = base64::encode(digest(&SHA256, relaxed_body(body).as_bytes()));
+        let value = format!(
+            "v=1; a={}; c=relaxed/relaxed; d={}; s={}; t={}; h={}; bh={}; b=",
+            self.key.algorithm(),
+            self.domain,
+            self.selector,
+            timestamp,
+            signed
+                .iter()
+                .map(|(name, _)| name.to_lowercase())
+                .collect::<Vec<_>>()
+                .join(":"),
+            body_hash
+        );
+
+        let mut data = String::new();
+        for (name, field) in &signed {
+            data.push_str(&relaxed_header(name, field));
+            data.push_str("\r\n");
+        }
+        data.push_str(&relaxed_header("DKIM-Signature", &value));
+
+        let signature = base64::encode(self.key.sign(data.as_bytes())?);
+        Ok(format!(
+            "DKIM-Signature: {}{}\r\n{}",
+            value, signature, message
+        ))
+    }
+}
+
+/// Build the signer from the `dkim_*` settings.
+/// Signing is disabled when `dkim_key_path` is not set.
+pub fn from_settings(settings: &Settings) -> Result<Option<DkimSigner>, ApiError> {
+    let path = match &settings.dkim_key_path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let missing = |name: &str| ApiError::InternalError(format!("Missing setting : {}", name));
+
+    Ok(Some(DkimSigner::new(
+        settings
+            .dkim_domain
+            .as_ref()
+            .ok_or_else(|| missing("dkim_domain"))?,
+        settings
+            .dkim_selector
+            .as_ref()
+            .ok_or_else(|| missing("dkim_selector"))?,
+        &settings.dkim_headers,
+        DkimKey::from_file(path, &settings.dkim_algorithm)?,
+    )))
+}
+
+fn split_message(message: &str) -> (&str, &str) {
+    match message.find("\r\n\r\n") {
+        Some(i) => (&message[..i + 2], &message[i + 4..]),
+        None => (message, ""),
+    }
+}
+
+/// Unfolded (name, value) pairs of a header block
+fn header_fields(block: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in block.split("\r\n").filter(|line| !line.is_empty()) {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(last) = fields.last_mut() {
+                last.1.push_str("\r\n");
+                last.1.push_str(line);
+            }
+        } else if let Some(i) = line.find(':') {
+            fields.push((line[..i].to_owned(), line[i + 1..].to_owned()));
+        }
+    }
+    fields
+}
+
+fn collapse_whitespace(s: &str) -> String {
+    s.split([' ', '\t'])
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn relaxed_header(name: &str, value: &str) -> String {
+    let value = value.replace("\r\n", "");
+    format!(
+        "{}:{}",
+        name.trim().to_lowercase(),
+        collapse_whitespace(&value)
+    )
+}
+
+fn relaxed_body(body: &str) -> String {
+    let mut lines: Vec<String> = body
+        .split("\r\n")
+        .map(|line| {
+            let trimmed = line.trim_end_matches([' ', '\t']);
+            let mut collapsed = collapse_whitespace(trimmed);
+            if trimmed.starts_with([' ', '\t']) {
+                collapsed.insert(0, ' ');
+            }
+            collapsed
+        })
+        .collect();
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    if lines.is_empty() {
+        return String::new();
+    }
+    lines.join("\r\n") + "\r\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{KeyPair, UnparsedPublicKey, ED25519};
+
+    const MESSAGE: &str = "From: <noreply@skeleton.test>\r\nTo: <user@skeleton.test>\r\nSubject:  Hello\r\n  World\r\n\r\nHi  there \r\n\r\n\r\n";
+
+    fn tag<'a>(header: &'a str, name: &str) -> &'a str {
+        header
+            .split(';')
+            .map(str::trim)
+            .find(|t| t.starts_with(&format!("{}=", name)))
+            .map(|t| &t[name.len() + 1..])
+            .unwrap()
+    }
+
+    #[test]
+    fn relaxed_canonicalization() {
+        assert_eq!(
+            relaxed_header("Subject", "  Hello\r\n  World"),
+            "subject:Hello World"
+        );
+        assert_eq!(relaxed_body("Hi  there \r\n\r\n\r\n"), "Hi there\r\n");
+        assert_eq!(relaxed_body("\r\n\r\n"), "");
+    }
+
+    /// A key of `testdata`, PKCS#1 or PKCS#8 depending on `file`
+    fn rsa_signer(file: &str) -> DkimSigner {
+        let path = format!("{}/src/mails/testdata/{}", env!("CARGO_MANIFEST_DIR"), file);
+        DkimSigner::new(
+            "skeleton.test",
+            "mail",
+            "from:to:subject:date",
+            DkimKey::from_file(&path, "rsa").unwrap(),
+        )
+    }
+
+    /// Signature of `MESSAGE` at `t=1600000000` by the `testdata` key,
+    /// canonicalized by hand and signed with `openssl dgst -sha256 -sign`
+    const RSA_SIGNATURE: &str = "DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/relaxed; d=skeleton.test; s=mail; t=1600000000; h=from:to:subject; bh=LaegeaE4sWd4l9K7YWNlAinmqUePEZwKG9dMjiYmLn8=; b=q8CqMQqHd1VrCCGR0iVMlqhdLKyiPcmPsmCPZpyHMZF2yETA6RyR0BDuB+h868dSOQhFQpX8lhNBMLmfs51AG69WHDtOyz2zODdqpYgPKCtggPQzyoeNCSrvfpr+ShHPXm6ds6A9qLU+h5gOoLY2TKdRqr2A0NNNGy5Sgp5xbbEcbgTiqkgWt0ar30vu0dFzjcwoWm7TYg49UXDpTQijBvVS7uCscAyafsAQCWuxWRDNlx9QmrzJs5NaBatWM5RRhyL/BGiylaAmvbqpPOoDImTWOMDTZdOLpR39LSsM1U6EEhV0XsMwkpN6Z9CaOEmREsYXcKaG/jm7OtmjBnpwUw==\r\n";
+
+    #[test]
+    fn rsa_signature_matches_a_known_vector() {
+        for file in &["dkim_rsa.pem", "dkim_rsa_pkcs8.pem"] {
+            let signed = rsa_signer(file).sign_at(MESSAGE, 1_600_000_000).unwrap();
+            assert_eq!(signed, format!("{}{}", RSA_SIGNATURE, MESSAGE), "{}", file);
+        }
+    }
+
+    #[test]
+    fn rsa_keys_are_parsed_by_their_pem_label() {
+        let dir = format!("{}/src/mails/testdata", env!("CARGO_MANIFEST_DIR"));
+        let pkcs1 = fs::read_to_string(format!("{}/dkim_rsa.pem", dir)).unwrap();
+
+        // a PKCS#1 key labelled as PKCS#8 is rejected
+        let mislabelled = std::env::temp_dir().join(format!("dkim-{}.pem", uuid::Uuid::new_v4()));
+        fs::write(
+            &mislabelled,
+            pkcs1.replace("RSA PRIVATE KEY", "PRIVATE KEY"),
+        )
+        .unwrap();
+        assert!(DkimKey::from_file(mislabelled.to_str().unwrap(), "rsa").is_err());
+        fs::remove_file(mislabelled).unwrap();
+
+        assert!(DkimKey::from_file(&format!("{}/dkim_rsa.pem", dir), "ed25519").is_err());
+    }
+
+    #[test]
+    fn ed25519_signature_can_be_verified() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public = pair.public_key().as_ref().to_vec();
+        let signer = DkimSigner::new(
+            "skeleton.test",
+            "mail",
+            "from:to:subject:date",
+            DkimKey::Ed25519(pair),
+        );
+
+        let signed = signer.sign(MESSAGE).unwrap();
+        assert!(signed.ends_with(MESSAGE));
+
+        let (block, body) = split_message(&signed);
+        let fields = header_fields(block);
+        let (_, value) = &fields[0];
+        assert_eq!(tag(value, "a"), "ed25519-sha256");
+        assert_eq!(tag(value, "d"), "skeleton.test");
+        assert_eq!(tag(value, "h"), "from:to:subject");
+        assert_eq!(
+            tag(value, "bh"),
+            base64::encode(digest(&SHA256, relaxed_body(body).as_bytes()))
+        );
+
+        // rebuild the signed data like a receiver would
+        let signature = base64::decode(tag(value, "b")).unwrap();
+        let unsigned = &value[..value.rfind("b=").unwrap() + 2];
+        let mut data = String::new();
+        for name in tag(value, "h").split(':') {
+            let (n, v) = fields
+                .iter()
+                .find(|(n, _)| n.to_lowercase() == name)
+                .unwrap();
+            data.push_str(&relaxed_header(n, v));
+            data.push_str("\r\n");
+        }
+        data.push_str(&relaxed_header("DKIM-Signature", unsigned));
+
+        UnparsedPublicKey::new(&ED25519, public)
+            .verify(digest(&SHA256, data.as_bytes()).as_ref(), &signature)
+            .unwrap();
+    }
+}
@@ -1,3 +1,6 @@
+pub mod dkim;
+pub mod text;
+pub mod transport;
 pub mod user;
 
 use actix::prelude::*;
@@ -5,42 +8,353 @@ use actix::Addr;
 
 extern crate lettre;
 
-use lettre::{smtp::authentication::Credentials, SmtpClient, Transport};
-use lettre_email::EmailBuilder;
+use lettre_email::{Email, EmailBuilder, Header, MimeMessage, MimeMultipartType, PartBuilder};
 
-use log::info;
-use std::env;
+use log::{error, info, warn};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{field::Empty, info_span, Span};
 
 use crate::errors::ApiError;
+use crate::metrics::{MAIL_FAILED, MAIL_QUEUED, MAIL_SENT};
+use crate::settings::Settings;
+use dkim::DkimSigner;
+use transport::MailTransport;
 
-#[derive(Message)]
+#[derive(Message, Default)]
 #[rtype(result = "Result<bool, std::io::Error>")]
 pub struct SendableEmail {
-    to: String,
-    title: String,
-    content: String,
+    pub to: String,
+    pub title: String,
+    /// html body
+    pub content: String,
+    /// plain text body, derived from the html one when not provided
+    pub text: Option<String>,
+    pub cc: Vec<String>,
+    pub bcc: Vec<String>,
+    /// defaults to `MAIL_REPLY_TO` when set
+    pub reply_to: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub attachments: Vec<Attachment>,
+}
+
+impl SendableEmail {
+    /// The html body with its `cid:` references replaced by `data:` URIs of the
+    /// inline attachments, so that a browser can display it
+    pub fn browsable_html(&self) -> String {
+        self.attachments
+            .iter()
+            .filter_map(|attachment| Some((attachment.content_id.as_ref()?, attachment)))
+            .fold(self.content.clone(), |html, (id, attachment)| {
+                html.replace(
+                    &format!("cid:{}", id),
+                    &format!(
+                        "data:{};base64,{}",
+                        attachment.content_type,
+                        base64::encode(&attachment.content)
+                    ),
+                )
+            })
+    }
+}
+
+/// A file joined to a mail.
+/// With a `content_id` it is an inline part, usable in the html as `cid:<content_id>`.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: String,
+    pub content: Vec<u8>,
+    pub content_id: Option<String>,
+}
+
+impl Attachment {
+    /// The type of the attachment comes from the extension of `filename`
+    pub fn new(filename: &str, content: Vec<u8>, content_id: Option<&str>) -> Self {
+        Attachment {
+            filename: filename.to_owned(),
+            content_type: content_type_of(filename).to_owned(),
+            content,
+            content_id: content_id.map(|id| id.to_owned()),
+        }
+    }
+
+    fn to_part(&self) -> MimeMessage {
+        let disposition = match self.content_id {
+            Some(_) => "inline",
+            None => "attachment",
+        };
+        let mut part = PartBuilder::new()
+            .body(base64_lines(&self.content))
+            .header(("Content-Type", self.content_type.as_str()))
+            .header((
+                "Content-Disposition",
+                format!("{}; filename=\"{}\"", disposition, self.filename),
+            ))
+            .header(("Content-Transfer-Encoding", "base64"));
+        if let Some(id) = &self.content_id {
+            part = part.header(("Content-ID", format!("<{}>", id)));
+        }
+        part.build()
+    }
+}
+
+fn content_type_of(filename: &str) -> &'static str {
+    let extension = filename.rsplit('.').next().unwrap_or("").to_lowercase();
+    match extension.as_ref() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        "html" => "text/html",
+        _ => "application/octet-stream",
+    }
 }
 
-pub fn send_mail(mail: SendableEmail) -> Result<(), ApiError> {
-    let email = EmailBuilder::new()
+/// base64 wrapped at 76 columns, as required for mail bodies
+fn base64_lines(content: &[u8]) -> String {
+    base64::encode(content)
+        .as_bytes()
+        .chunks(76)
+        .map(|line| String::from_utf8_lossy(line).into_owned())
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+/// Who the mails come from, unless a mail says otherwise
+#[derive(Debug, Clone)]
+pub struct Sender {
+    pub from: String,
+    pub reply_to: Option<String>,
+}
+
+impl Sender {
+    /// `mail_from`, falling back to the smtp credential, and `mail_reply_to`
+    pub fn from_settings(settings: &Settings) -> Result<Self, ApiError> {
+        Ok(Sender {
+            from: settings
+                .mail_from
+                .as_ref()
+                .or(settings.smtp_credential.as_ref())
+                .ok_or_else(|| ApiError::InternalError("Missing setting : mail_from".to_owned()))?
+                .to_owned(),
+            reply_to: settings.mail_reply_to.to_owned(),
+        })
+    }
+}
+
+pub fn build_email(mail: SendableEmail, sender: &Sender) -> Result<Email, ApiError> {
+    let from = sender.from.to_owned();
+    let text = match mail.text {
+        Some(text) => text,
+        None => text::html_to_text(&mail.content),
+    };
+
+    let alternative = PartBuilder::new()
+        .message_type(MimeMultipartType::Alternative)
+        .child(
+            PartBuilder::new()
+                .body(text)
+                .header(("Content-Type", "text/plain; charset=utf-8"))
+                .build(),
+        )
+        .child(
+            PartBuilder::new()
+                .body(mail.content)
+                .header(("Content-Type", "text/html; charset=utf-8"))
+                .build(),
+        )
+        .build();
+
+    let (inline, attached): (Vec<_>, Vec<_>) = mail
+        .attachments
+        .iter()
+        .partition(|attachment| attachment.content_id.is_some());
+
+    let body = if inline.is_empty() {
+        alternative
+    } else {
+        // there is no multipart/related in MimeMultipartType, so the header is set by hand
+        let mut related = MimeMessage::new_blank_message();
+        related.headers.insert(Header::new(
+            "Content-Type".to_owned(),
+            format!("multipart/related; boundary=\"{}\"", related.boundary),
+        ));
+        related.children.push(alternative);
+        related
+            .children
+            .extend(inline.iter().map(|attachment| attachment.to_part()));
+        related
+    };
+
+    let mut email = EmailBuilder::new()
         .to(mail.to)
-        .from(env::var("SMTP_CREDENTIAL")?)
+        .from(from)
         .subject(mail.title)
-        .html(mail.content)
-        .build()?;
+        .message_type(MimeMultipartType::Mixed)
+        .child(body);
 
-    let mut mailer = SmtpClient::new_simple(&env::var("SMTP_URL")?)?
-        .credentials(Credentials::new(
-            env::var("SMTP_CREDENTIAL")?,
-            env::var("SMTP_PASSWORD")?,
-        ))
-        .transport();
+    for attachment in attached {
+        email = email.child(attachment.to_part());
+    }
+    for cc in mail.cc {
+        email = email.cc(cc);
+    }
+    for bcc in mail.bcc {
+        email = email.bcc(bcc);
+    }
+    if let Some(reply_to) = mail.reply_to.or_else(|| sender.reply_to.to_owned()) {
+        email = email.reply_to(reply_to);
+    }
+    for header in mail.headers {
+        email = email.header(header);
+    }
+
+    Ok(email.build()?)
+}
+
+pub fn send_mail(
+    mail: SendableEmail,
+    sender: &Sender,
+    transport: &mut dyn MailTransport,
+    dkim: Option<&DkimSigner>,
+) -> Result<(), ApiError> {
+    let email: lettre::SendableEmail = build_email(mail, sender)?.into();
+    let email = match dkim {
+        Some(signer) => {
+            let envelope = email.envelope().clone();
+            let id = email.message_id().to_owned();
+            let signed = signer.sign(&email.message_to_string()?)?;
+            lettre::SendableEmail::new(envelope, id, signed.into_bytes())
+        }
+        None => email,
+    };
+    transport.send(email)
+}
 
-    mailer.send(email.into())?;
-    Ok(())
+/// What the postman did with the mails it accepted
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MailSummary {
+    pub sent: usize,
+    pub failed: usize,
+    /// kept in the spool, to be sent by hand once the api is stopped
+    pub spooled: usize,
+    /// neither sent nor spooled : the shutdown timeout expired without a spool,
+    /// or writing to the spool failed
+    pub dropped: usize,
 }
 
-pub struct Postman;
+impl fmt::Display for MailSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} mails sent, {} failed, {} spooled, {} dropped",
+            self.sent, self.failed, self.spooled, self.dropped
+        )
+    }
+}
+
+pub struct Postman {
+    sender: Sender,
+    transport: Box<dyn MailTransport>,
+    dkim: Option<DkimSigner>,
+    spool: Option<Box<dyn MailTransport>>,
+    /// set on shutdown, mails handled past it are spooled
+    deadline: Arc<Mutex<Option<Instant>>>,
+    summary: MailSummary,
+}
+
+impl Postman {
+    pub fn new(
+        sender: Sender,
+        transport: Box<dyn MailTransport>,
+        dkim: Option<DkimSigner>,
+    ) -> Self {
+        Postman {
+            sender,
+            transport,
+            dkim,
+            spool: None,
+            deadline: Arc::new(Mutex::new(None)),
+            summary: MailSummary::default(),
+        }
+    }
+
+    /// Where the queued mails go when the shutdown timeout expires
+    pub fn with_spool(mut self, spool: Option<Box<dyn MailTransport>>) -> Self {
+        self.spool = spool;
+        self
+    }
+
+    /// Handle to stop the postman, to take before starting it
+    pub fn shutdown(&self) -> PostmanShutdown {
+        PostmanShutdown {
+            deadline: self.deadline.clone(),
+        }
+    }
+
+    fn deliver(&mut self, email: SendableEmail) -> Result<(), ApiError> {
+        let late = match *self
+            .deadline
+            .lock()
+            .map_err(|_| ApiError::InternalError("Postman deadline is poisoned".to_owned()))?
+        {
+            Some(deadline) => Instant::now() >= deadline,
+            None => false,
+        };
+        let transport = match (late, &mut self.spool) {
+            (false, _) => self.transport.as_mut(),
+            (true, Some(spool)) => {
+                let to = email.to.clone();
+                return match send_mail(email, &self.sender, spool.as_mut(), self.dkim.as_ref()) {
+                    Ok(()) => {
+                        self.summary.spooled += 1;
+                        Ok(())
+                    }
+                    Err(e) => {
+                        self.summary.dropped += 1;
+                        Err(ApiError::InternalError(format!(
+                            "Mail to {} dropped, not spooled : {}",
+                            to, e
+                        )))
+                    }
+                };
+            }
+            (true, None) => {
+                self.summary.dropped += 1;
+                return Err(ApiError::InternalError(format!(
+                    "Mail to {} dropped, no spool configured",
+                    email.to
+                )));
+            }
+        };
+        let span = info_span!(
+            "mail.deliver",
+            otel.kind = "client",
+            otel.status_code = Empty
+        );
+        let (sender, dkim) = (&self.sender, self.dkim.as_ref());
+        let sent = span.in_scope(|| send_mail(email, sender, transport, dkim));
+        if sent.is_err() {
+            span.record("otel.status_code", "ERROR");
+        }
+        match sent {
+            Ok(()) => {
+                self.summary.sent += 1;
+                MAIL_SENT.inc(&[]);
+                Ok(())
+            }
+            Err(e) => {
+                self.summary.failed += 1;
+                MAIL_FAILED.inc(&[]);
+                Err(e)
+            }
+        }
+    }
+}
 
 impl Actor for Postman {
     type Context = Context<Self>;
@@ -59,21 +373,283 @@ impl Handler<SendableEmail> for Postman {
 
     fn handle(&mut self, email: SendableEmail, _ctx: &mut Context<Self>) -> Self::Result {
         info!("SendableEmail received, processing...");
-        match send_mail(email) {
+        match self.deliver(email) {
             Ok(()) => Ok(true),
             Err(err) => {
-                info!("Error on send email : {}", err);
+                error!("Error on send email : {}", err);
                 Ok(false)
             }
         }
     }
 }
 
+/// A mail posted while handling a request, sent in a span child of the request one
+#[derive(Message)]
+#[rtype(result = "Result<bool, std::io::Error>")]
+struct Queued {
+    email: SendableEmail,
+    span: Span,
+}
+
+impl Handler<Queued> for Postman {
+    type Result = Result<bool, std::io::Error>;
+
+    fn handle(&mut self, queued: Queued, ctx: &mut Context<Self>) -> Self::Result {
+        let span = info_span!(parent: &queued.span, "mail", to = %queued.email.to);
+        let _enter = span.enter();
+        <Self as Handler<SendableEmail>>::handle(self, queued.email, ctx)
+    }
+}
+
+/// Answered once every mail queued before it is handled, the mailbox being FIFO
+#[derive(Message)]
+#[rtype(result = "MailSummary")]
+struct Flush;
+
+impl Handler<Flush> for Postman {
+    type Result = MessageResult<Flush>;
+
+    fn handle(&mut self, _: Flush, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.summary)
+    }
+}
+
+/// Drains the postman mailbox when the api stops
+pub struct PostmanShutdown {
+    deadline: Arc<Mutex<Option<Instant>>>,
+}
+
+impl PostmanShutdown {
+    /// Send the queued mails for up to `timeout`,
+    /// then spool the remaining ones (or drop them without a spool).
+    pub async fn run(
+        &self,
+        postman: &Addr<Postman>,
+        timeout: Duration,
+    ) -> Result<MailSummary, ApiError> {
+        *self
+            .deadline
+            .lock()
+            .map_err(|_| ApiError::InternalError("Postman deadline is poisoned".to_owned()))? =
+            Some(Instant::now() + timeout);
+
+        let summary = postman.send(Flush).await?;
+        if summary.spooled + summary.dropped > 0 {
+            warn!("Mails still queued after {:?} : {}", timeout, summary);
+        }
+        Ok(summary)
+    }
+}
+
+/// Wait until the mails queued so far are handled
+#[cfg(test)]
+pub async fn flush(postman: &Addr<Postman>) -> Result<MailSummary, ApiError> {
+    Ok(postman.send(Flush).await?)
+}
+
 pub fn post_email(email: SendableEmail, actor: &Addr<Postman>) -> Result<(), ApiError> {
-    match actor.try_send(email) {
-        Ok(()) => Ok(()),
+    match actor.try_send(Queued {
+        email,
+        span: Span::current(),
+    }) {
+        Ok(()) => {
+            MAIL_QUEUED.inc(&[]);
+            Ok(())
+        }
         Err(_) => Err(ApiError::InternalError(
             "The postman can't send email".into(),
         )),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport::MemoryTransport;
+
+    fn sender() -> Sender {
+        Sender {
+            from: "noreply@skeleton.test".to_owned(),
+            reply_to: None,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn postman_delivers_through_its_transport() {
+        let transport = MemoryTransport::new();
+        let outbox = transport.outbox();
+        let postman = Postman::new(sender(), Box::new(transport), None).start();
+
+        let sent = postman
+            .send(SendableEmail {
+                to: "user@skeleton.test".into(),
+                title: "Hello".into(),
+                content: "<p>World</p>".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(sent);
+        let mails = outbox.lock().unwrap();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].to, vec!["user@skeleton.test".to_owned()]);
+        assert!(mails[0].message.contains("Subject: Hello"));
+    }
+
+    fn queue(postman: &Addr<Postman>, count: usize) {
+        for i in 0..count {
+            post_email(
+                SendableEmail {
+                    to: format!("user{}@skeleton.test", i),
+                    title: "Hello".into(),
+                    content: "<p>World</p>".into(),
+                    ..Default::default()
+                },
+                postman,
+            )
+            .unwrap();
+        }
+    }
+
+    #[actix_rt::test]
+    async fn shutdown_delivers_every_accepted_mail() {
+        let transport = MemoryTransport::new();
+        let outbox = transport.outbox();
+        let postman = Postman::new(sender(), Box::new(transport), None);
+        let shutdown = postman.shutdown();
+        let postman = postman.start();
+
+        queue(&postman, 10);
+        let summary = shutdown
+            .run(&postman, Duration::from_secs(30))
+            .await
+            .unwrap();
+
+        assert_eq!(summary.sent, 10);
+        assert_eq!(outbox.lock().unwrap().len(), 10);
+    }
+
+    /// Takes its time, like a remote smtp server
+    struct SlowTransport(MemoryTransport);
+
+    impl MailTransport for SlowTransport {
+        fn send(&mut self, email: lettre::SendableEmail) -> Result<(), ApiError> {
+            std::thread::sleep(Duration::from_millis(20));
+            self.0.send(email)
+        }
+    }
+
+    #[actix_rt::test]
+    async fn shutdown_spools_mails_left_after_timeout() {
+        let transport = MemoryTransport::new();
+        let outbox = transport.outbox();
+        let spool = MemoryTransport::new();
+        let spooled = spool.outbox();
+        let postman = Postman::new(sender(), Box::new(SlowTransport(transport)), None)
+            .with_spool(Some(Box::new(spool)));
+        let shutdown = postman.shutdown();
+        let postman = postman.start();
+
+        queue(&postman, 10);
+        let summary = shutdown
+            .run(&postman, Duration::from_millis(50))
+            .await
+            .unwrap();
+
+        assert!(summary.sent > 0 && summary.spooled > 0);
+        assert_eq!(summary.sent + summary.spooled, 10);
+        assert_eq!(summary.dropped, 0);
+        assert_eq!(outbox.lock().unwrap().len(), summary.sent);
+        assert_eq!(spooled.lock().unwrap().len(), summary.spooled);
+    }
+
+    /// A spool on a full disk
+    struct BrokenTransport;
+
+    impl MailTransport for BrokenTransport {
+        fn send(&mut self, _: lettre::SendableEmail) -> Result<(), ApiError> {
+            Err(ApiError::InternalError(
+                "No space left on device".to_owned(),
+            ))
+        }
+    }
+
+    #[actix_rt::test]
+    async fn failed_spool_writes_are_dropped_mails() {
+        let postman = Postman::new(sender(), Box::new(MemoryTransport::new()), None)
+            .with_spool(Some(Box::new(BrokenTransport)));
+        let shutdown = postman.shutdown();
+        let postman = postman.start();
+
+        queue(&postman, 3);
+        let summary = shutdown
+            .run(&postman, Duration::from_secs(0))
+            .await
+            .unwrap();
+
+        assert_eq!(summary.spooled, 0);
+        assert_eq!(summary.dropped, 3);
+    }
+
+    #[test]
+    fn email_has_text_alternative_and_attachments() {
+        let email = build_email(
+            SendableEmail {
+                to: "user@skeleton.test".into(),
+                title: "Hello".into(),
+                content: "<p>World</p><img src=\"cid:logo\">".into(),
+                cc: vec!["cc@skeleton.test".into()],
+                bcc: vec!["bcc@skeleton.test".into()],
+                reply_to: Some("support@skeleton.test".into()),
+                headers: vec![("X-Mailer".into(), "skeleton".into())],
+                attachments: vec![
+                    Attachment {
+                        filename: "logo.png".into(),
+                        content_type: "image/png".into(),
+                        content: vec![0; 100],
+                        content_id: Some("logo".into()),
+                    },
+                    Attachment {
+                        filename: "invoice.pdf".into(),
+                        content_type: "application/pdf".into(),
+                        content: b"%PDF".to_vec(),
+                        content_id: None,
+                    },
+                ],
+                ..Default::default()
+            },
+            &sender(),
+        )
+        .unwrap();
+
+        let sendable: lettre::SendableEmail = email.into();
+        assert_eq!(sendable.envelope().to().len(), 3);
+        let message = sendable.message_to_string().unwrap();
+
+        assert!(message.contains("multipart/mixed"));
+        assert!(message.contains("multipart/related"));
+        assert!(message.contains("multipart/alternative"));
+        assert!(message.contains("text/plain; charset=utf-8\r\n\r\nWorld"));
+        assert!(message.contains("Content-ID: <logo>"));
+        assert!(message.contains("attachment; filename=\"invoice.pdf\""));
+        assert!(message.contains("Cc: <cc@skeleton.test>"));
+        assert!(!message.contains("Bcc"));
+        assert!(message.contains("Reply-To: <support@skeleton.test>"));
+        assert!(message.contains("X-Mailer: skeleton"));
+    }
+
+    #[test]
+    fn inline_attachments_become_data_uris_for_browsers() {
+        let email = SendableEmail {
+            content: "<img src=\"cid:logo\"><img src=\"cid:other\">".into(),
+            attachments: vec![Attachment::new("logo.png", vec![1, 2, 3], Some("logo"))],
+            ..Default::default()
+        };
+        assert_eq!(
+            email.browsable_html(),
+            "<img src=\"data:image/png;base64,AQID\"><img src=\"cid:other\">"
+        );
+    }
+}
@@ -0,0 +1,111 @@
+/// Derive a plain text version of an html mail.
+/// Links are kept as `text (url)`, block elements become line breaks,
+/// and `head`, `style` and comments are dropped.
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    let mut href: Option<String> = None;
+
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if rest.starts_with("<!--") {
+            rest = skip_after(rest, "-->");
+            continue;
+        }
+
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("")
+            .to_lowercase();
+        let closing = tag.starts_with('/');
+
+        match name.as_ref() {
+            "head" | "style" | "script" | "title" if !closing => {
+                rest = skip_after(rest, &format!("</{}>", name));
+            }
+            "br" | "p" | "div" | "tr" | "table" | "h1" | "h2" | "h3" | "hr" | "li" => {
+                text.push('\n')
+            }
+            "a" if !closing => href = attribute(tag, "href"),
+            "a" => {
+                if let Some(url) = href.take() {
+                    text.push_str(&format!(" ({})", url));
+                }
+            }
+            _ => (),
+        }
+    }
+    text.push_str(rest);
+
+    decode_entities(&text)
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|paragraph| !paragraph.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// The rest of `s` after `pattern`, an ascii pattern matched ignoring the case.
+/// Searched in `s` itself : lowercasing may change the length of other characters.
+fn skip_after<'a>(s: &'a str, pattern: &str) -> &'a str {
+    let found = s.char_indices().find(|(i, _)| {
+        s.get(*i..i + pattern.len())
+            .is_some_and(|candidate| candidate.eq_ignore_ascii_case(pattern))
+    });
+    match found {
+        Some((i, _)) => &s[i + pattern.len()..],
+        None => "",
+    }
+}
+
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let start = tag.find(&format!("{}=\"", name))? + name.len() + 2;
+    let end = tag[start..].find('"')?;
+    Some(tag[start..start + end].to_owned())
+}
+
+fn decode_entities(s: &str) -> String {
+    s.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_is_converted_to_text() {
+        let html = r#"<html><head><style>p { color: red; }</style><title>Mail</title></head>
+            <body><!-- LOGO --><h1>Welcome,   John</h1>
+            <p>You&nbsp;are now registered.</p>
+            <a href="https://skeleton.test/reset">Reset your password</a></body></html>"#;
+
+        assert_eq!(
+            html_to_text(html),
+            "Welcome, John\n\nYou are now registered.\n\nReset your password (https://skeleton.test/reset)"
+        );
+
+        // İ and ẞ grow when lowercased, the end of the style must still be found
+        let html = "<STYLE>p::before { content: \"İİẞ\" }</STYLE><p>Ünïcode</p>";
+        assert_eq!(html_to_text(html), "Ünïcode");
+    }
+}
@@ -0,0 +1,337 @@
+use lettre::smtp::authentication::Credentials;
+use lettre::smtp::ConnectionReuseParameters;
+use lettre::{
+    ClientSecurity, ClientTlsParameters, SendableEmail, SmtpClient, SmtpTransport, Transport,
+};
+use native_tls::{Protocol, TlsConnector};
+
+use std::fs;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::errors::ApiError;
+use crate::settings::Settings;
+
+/// A way to deliver a built email.
+/// The [`Postman`](super::Postman) actor owns one and uses it for every mail.
+pub trait MailTransport: Send {
+    fn send(&mut self, email: SendableEmail) -> Result<(), ApiError>;
+
+    /// Whether mails can be delivered right now, used by the readiness probe
+    fn check(&mut self) -> Result<(), ApiError> {
+        Ok(())
+    }
+}
+
+/// How the SMTP connection is secured
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmtpSecurity {
+    /// TLS wrapped connection, usually on port 465
+    Tls,
+    /// Plain connection upgraded with STARTTLS, usually on port 587
+    StartTls,
+    /// No encryption at all, only for local relays
+    None,
+}
+
+impl SmtpSecurity {
+    fn default_port(self) -> u16 {
+        match self {
+            SmtpSecurity::Tls => lettre::smtp::SUBMISSIONS_PORT,
+            SmtpSecurity::StartTls => lettre::smtp::SUBMISSION_PORT,
+            SmtpSecurity::None => lettre::smtp::SMTP_PORT,
+        }
+    }
+}
+
+impl std::str::FromStr for SmtpSecurity {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "tls" => Ok(SmtpSecurity::Tls),
+            "starttls" => Ok(SmtpSecurity::StartTls),
+            "none" => Ok(SmtpSecurity::None),
+            _ => Err(ApiError::InternalError(format!(
+                "Unknown SMTP security : {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Sends mails through an SMTP server, keeping the connection open between mails.
+pub struct SmtpMailTransport {
+    transport: SmtpTransport,
+    host: String,
+    port: u16,
+}
+
+impl SmtpMailTransport {
+    pub fn new(
+        host: &str,
+        port: Option<u16>,
+        security: SmtpSecurity,
+        credentials: Option<(String, String)>,
+    ) -> Result<Self, ApiError> {
+        let port = port.unwrap_or_else(|| security.default_port());
+
+        let client_security = match security {
+            SmtpSecurity::None => ClientSecurity::None,
+            _ => {
+                let connector = TlsConnector::builder()
+                    .min_protocol_version(Some(Protocol::Tlsv12))
+                    .build()?;
+                let parameters = ClientTlsParameters::new(host.to_owned(), connector);
+                match security {
+                    SmtpSecurity::Tls => ClientSecurity::Wrapper(parameters),
+                    _ => ClientSecurity::Required(parameters),
+                }
+            }
+        };
+
+        let mut client = SmtpClient::new((host, port), client_security)?
+            .connection_reuse(ConnectionReuseParameters::ReuseUnlimited);
+        if let Some((user, password)) = credentials {
+            client = client.credentials(Credentials::new(user, password));
+        }
+
+        Ok(SmtpMailTransport {
+            transport: client.transport(),
+            host: host.to_owned(),
+            port,
+        })
+    }
+}
+
+impl MailTransport for SmtpMailTransport {
+    fn send(&mut self, email: SendableEmail) -> Result<(), ApiError> {
+        match self.transport.send(email) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                // a broken connection must not be reused for the next mail
+                self.transport.close();
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Only checks that the server accepts connections, lettre doesn't expose more
+    fn check(&mut self) -> Result<(), ApiError> {
+        let address = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| ApiError::InternalError(format!("Unknown SMTP host : {}", self.host)))?;
+        TcpStream::connect_timeout(&address, Duration::from_secs(2))?;
+        Ok(())
+    }
+}
+
+/// Writes every mail as a file in a maildir (`tmp`, `new` and `cur` folders).
+pub struct MaildirTransport {
+    path: PathBuf,
+}
+
+impl MaildirTransport {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Result<Self, ApiError> {
+        let path = path.into();
+        for dir in &["tmp", "new", "cur"] {
+            fs::create_dir_all(path.join(dir))?;
+        }
+        Ok(MaildirTransport { path })
+    }
+}
+
+impl MailTransport for MaildirTransport {
+    fn send(&mut self, email: SendableEmail) -> Result<(), ApiError> {
+        let name = format!(
+            "{}.{}.skeleton",
+            chrono::Utc::now().timestamp(),
+            uuid::Uuid::new_v4()
+        );
+        let message = email.message_to_string()?;
+
+        // maildir delivery : write in tmp, then move to new once complete
+        let tmp = self.path.join("tmp").join(&name);
+        fs::write(&tmp, message)?;
+        fs::rename(&tmp, self.path.join("new").join(&name))?;
+        Ok(())
+    }
+
+    fn check(&mut self) -> Result<(), ApiError> {
+        for dir in &["tmp", "new"] {
+            if fs::metadata(self.path.join(dir))?.permissions().readonly() {
+                return Err(ApiError::InternalError(format!(
+                    "Maildir is read only : {:?}",
+                    self.path
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Prints every mail on the standard output, useful for local development.
+pub struct StdoutTransport;
+
+impl MailTransport for StdoutTransport {
+    fn send(&mut self, email: SendableEmail) -> Result<(), ApiError> {
+        let to = email
+            .envelope()
+            .to()
+            .iter()
+            .map(|address| address.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        println!(
+            "----- mail to {} -----\n{}\n----- end of mail -----",
+            to,
+            email.message_to_string()?
+        );
+        Ok(())
+    }
+}
+
+/// A mail kept by the [`MemoryTransport`]
+#[allow(dead_code)] // read by tests through the outbox
+#[derive(Debug, Clone)]
+pub struct CapturedEmail {
+    pub from: Option<String>,
+    pub to: Vec<String>,
+    pub message: String,
+}
+
+/// Shared handle on the mails captured by a [`MemoryTransport`]
+pub type Outbox = Arc<Mutex<Vec<CapturedEmail>>>;
+
+/// Keeps every mail in memory so tests can assert against them.
+#[derive(Default)]
+pub struct MemoryTransport {
+    outbox: Outbox,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        MemoryTransport::default()
+    }
+
+    /// The captured mails, still readable once the transport is moved into the postman
+    #[allow(dead_code)]
+    pub fn outbox(&self) -> Outbox {
+        self.outbox.clone()
+    }
+}
+
+impl MailTransport for MemoryTransport {
+    fn send(&mut self, email: SendableEmail) -> Result<(), ApiError> {
+        let from = email.envelope().from().map(|address| address.to_string());
+        let to = email
+            .envelope()
+            .to()
+            .iter()
+            .map(|address| address.to_string())
+            .collect();
+        let message = email.message_to_string()?;
+
+        self.outbox
+            .lock()
+            .map_err(|_| ApiError::InternalError("Mail outbox is poisoned".to_owned()))?
+            .push(CapturedEmail { from, to, message });
+        Ok(())
+    }
+}
+
+/// Build the transport selected by `mail_transport` (smtp, maildir, stdout or memory)
+pub fn from_settings(settings: &Settings) -> Result<Box<dyn MailTransport>, ApiError> {
+    let kind = &settings.mail_transport;
+    let missing = |name: &str| ApiError::InternalError(format!("Missing setting : {}", name));
+
+    match kind.to_lowercase().as_ref() {
+        "smtp" => {
+            let credentials = match (&settings.smtp_credential, &settings.smtp_password) {
+                (Some(user), Some(password)) => Some((user.to_owned(), password.to_owned())),
+                (Some(_), None) => return Err(missing("smtp_password")),
+                _ => None,
+            };
+            Ok(Box::new(SmtpMailTransport::new(
+                settings
+                    .smtp_url
+                    .as_ref()
+                    .ok_or_else(|| missing("smtp_url"))?,
+                settings.smtp_port,
+                settings.smtp_security.parse()?,
+                credentials,
+            )?))
+        }
+        "maildir" | "file" => Ok(Box::new(MaildirTransport::new(
+            settings
+                .maildir_path
+                .as_ref()
+                .ok_or_else(|| missing("maildir_path"))?,
+        )?)),
+        "stdout" => Ok(Box::new(StdoutTransport)),
+        "memory" => Ok(Box::new(MemoryTransport::new())),
+        _ => Err(ApiError::InternalError(format!(
+            "Unknown mail transport : {}",
+            kind
+        ))),
+    }
+}
+
+/// The maildir of `mail_spool_path`, keeping the mails still queued when the api stops
+pub fn spool_from_settings(
+    settings: &Settings,
+) -> Result<Option<Box<dyn MailTransport>>, ApiError> {
+    match &settings.mail_spool_path {
+        Some(path) => Ok(Some(Box::new(MaildirTransport::new(path)?))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lettre::{EmailAddress, Envelope};
+
+    fn sample_email() -> SendableEmail {
+        SendableEmail::new(
+            Envelope::new(
+                Some(EmailAddress::new("from@skeleton.test".to_owned()).unwrap()),
+                vec![EmailAddress::new("to@skeleton.test".to_owned()).unwrap()],
+            )
+            .unwrap(),
+            "id".to_owned(),
+            b"Subject: Hello\r\n\r\nWorld".to_vec(),
+        )
+    }
+
+    #[test]
+    fn memory_transport_captures_mails() {
+        let mut transport = MemoryTransport::new();
+        let outbox = transport.outbox();
+        transport.send(sample_email()).unwrap();
+
+        let mails = outbox.lock().unwrap();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].to, vec!["to@skeleton.test".to_owned()]);
+        assert_eq!(mails[0].from.as_deref(), Some("from@skeleton.test"));
+        assert!(mails[0].message.contains("Subject: Hello"));
+    }
+
+    #[test]
+    fn maildir_transport_delivers_in_new() {
+        let path = std::env::temp_dir().join(format!("skeleton-maildir-{}", uuid::Uuid::new_v4()));
+        let mut transport = MaildirTransport::new(&path).unwrap();
+        transport.send(sample_email()).unwrap();
+
+        assert_eq!(fs::read_dir(path.join("tmp")).unwrap().count(), 0);
+        let delivered: Vec<_> = fs::read_dir(path.join("new")).unwrap().collect();
+        assert_eq!(delivered.len(), 1);
+        let content = fs::read_to_string(delivered[0].as_ref().unwrap().path()).unwrap();
+        assert!(content.contains("World"));
+
+        fs::remove_dir_all(path).unwrap();
+    }
+}
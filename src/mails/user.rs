@@ -1,39 +1,196 @@
 use crate::errors::ApiError;
-use crate::mails::SendableEmail;
-use crate::templates as template;
+use crate::i18n::Locales;
+use crate::mails::{Attachment, SendableEmail};
+use crate::templates::{self as template, Templates};
 
-use std::env;
+/// The logo shown at the top of `mail.html`, referenced as `cid:logo`
+fn logo(templates: &Templates) -> Result<Attachment, ApiError> {
+    let content = templates.asset("images/logo.png")?;
+    Ok(Attachment::new("logo.png", content.to_vec(), Some("logo")))
+}
 
-pub fn create_register_email(mail: &str, username: &str) -> Result<SendableEmail, ApiError> {
-    let content = template::mail::register_user(username)?;
+pub fn create_register_email(
+    templates: &Templates,
+    locales: &Locales,
+    locale: &str,
+    mail: &str,
+    username: &str,
+) -> Result<SendableEmail, ApiError> {
+    let content = template::mail::register_user(templates, locales, locale, username)?;
     Ok(SendableEmail {
         to: mail.into(),
-        title: format!("Welcome on {} !", env::var("PLATFORM_NAME")?),
-        content: content,
+        title: locales.t(
+            locale,
+            "register-subject",
+            &[("platform", templates.platform())],
+        ),
+        content,
+        attachments: vec![logo(templates)?],
+        ..Default::default()
     })
 }
 
 pub fn create_reset_token_email(
+    templates: &Templates,
+    locales: &Locales,
+    locale: &str,
     mail: &str,
     username: &str,
-    token: &str,
+    url: &str,
 ) -> Result<SendableEmail, ApiError> {
-    let content = template::mail::reset_token(mail, username, token)?;
+    let content = template::mail::reset_token(templates, locales, locale, mail, username, url)?;
     Ok(SendableEmail {
         to: mail.into(),
-        title: format!("{} : Password Reset", env::var("PLATFORM_NAME")?),
-        content: content,
+        title: locales.t(
+            locale,
+            "reset-subject",
+            &[("platform", templates.platform())],
+        ),
+        content,
+        attachments: vec![logo(templates)?],
+        ..Default::default()
     })
 }
 
 pub fn create_password_changed_success_email(
+    templates: &Templates,
+    locales: &Locales,
+    locale: &str,
     mail: &str,
     username: &str,
 ) -> Result<SendableEmail, ApiError> {
-    let content = template::mail::change_password_success(mail, username)?;
+    let content =
+        template::mail::change_password_success(templates, locales, locale, mail, username)?;
     Ok(SendableEmail {
         to: mail.into(),
-        title: format!("{} : Password Reset Success", env::var("PLATFORM_NAME")?),
-        content: content,
+        title: locales.t(
+            locale,
+            "password-changed-subject",
+            &[("platform", templates.platform())],
+        ),
+        content,
+        attachments: vec![logo(templates)?],
+        ..Default::default()
     })
 }
+
+/// Every mail the platform sends, so they can be previewed from the dashboard
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MailKind {
+    Register,
+    ResetToken,
+    PasswordChanged,
+}
+
+impl MailKind {
+    pub const ALL: [MailKind; 3] = [
+        MailKind::Register,
+        MailKind::ResetToken,
+        MailKind::PasswordChanged,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MailKind::Register => "register",
+            MailKind::ResetToken => "reset_token",
+            MailKind::PasswordChanged => "password_changed",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, ApiError> {
+        MailKind::ALL
+            .iter()
+            .find(|kind| kind.name() == name)
+            .copied()
+            .ok_or_else(|| ApiError::InternalError(format!("Unknown mail : {}", name)))
+    }
+}
+
+/// Values a mail is built from, sample ones when not supplied
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MailData {
+    pub email: String,
+    pub username: String,
+    pub url: String,
+}
+
+impl Default for MailData {
+    fn default() -> Self {
+        MailData {
+            email: "john.doe@example.com".to_owned(),
+            username: "John Doe".to_owned(),
+            url: "https://example.com/reset_password?token=sample".to_owned(),
+        }
+    }
+}
+
+pub fn create_email(
+    kind: MailKind,
+    templates: &Templates,
+    locales: &Locales,
+    locale: &str,
+    data: &MailData,
+) -> Result<SendableEmail, ApiError> {
+    match kind {
+        MailKind::Register => {
+            create_register_email(templates, locales, locale, &data.email, &data.username)
+        }
+        MailKind::ResetToken => create_reset_token_email(
+            templates,
+            locales,
+            locale,
+            &data.email,
+            &data.username,
+            &data.url,
+        ),
+        MailKind::PasswordChanged => create_password_changed_success_email(
+            templates,
+            locales,
+            locale,
+            &data.email,
+            &data.username,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn every_mail_kind_renders_with_sample_data() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let templates = Templates::load(root.join("templates"), "Skeleton").unwrap();
+        let locales = Locales::load(root.join("locales")).unwrap();
+
+        for kind in MailKind::ALL.iter() {
+            assert_eq!(MailKind::from_name(kind.name()).unwrap(), *kind);
+            let mail =
+                create_email(*kind, &templates, &locales, "fr", &MailData::default()).unwrap();
+            assert_eq!(mail.to, "john.doe@example.com");
+            assert!(mail.title.contains("Skeleton"));
+            assert!(mail.content.contains("lang=\"fr\""));
+        }
+        assert!(MailKind::from_name("unknown").is_err());
+    }
+
+    #[test]
+    fn user_values_are_escaped() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let templates = Templates::load(root.join("templates"), "Skeleton").unwrap();
+        let locales = Locales::load(root.join("locales")).unwrap();
+        let data = MailData {
+            username: "<b>Mallory</b>".to_owned(),
+            ..Default::default()
+        };
+
+        for kind in &[MailKind::ResetToken, MailKind::PasswordChanged] {
+            let mail = create_email(*kind, &templates, &locales, "en", &data).unwrap();
+            assert!(!mail.content.contains("<b>Mallory"));
+            assert!(mail.content.contains("&lt;b&gt;Mallory&lt;/b&gt;"));
+            assert!(crate::mails::text::html_to_text(&mail.content).contains("<b>Mallory</b>"));
+        }
+    }
+}
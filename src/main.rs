@@ -1,5 +1,4 @@
-use actix_files::Files;
-use actix_web::{middleware, web, App, HttpServer};
+use actix_web::HttpServer;
 
 #[macro_use]
 extern crate diesel;
@@ -12,25 +11,130 @@ extern crate serde_json;
 extern crate chrono;
 
 use dotenv::dotenv;
-use std::env;
 
+mod app;
 mod db;
 mod errors;
 mod handlers;
+mod i18n;
+mod logging;
 mod mails;
+mod metrics;
 mod middlewares;
+mod server;
+mod settings;
+mod telemetry;
 mod templates;
+#[cfg(test)]
+mod testing;
+mod urls;
 
 use crate::db as database;
-use crate::handlers as handler;
-use crate::middlewares::session::Level;
 
 use actix::prelude::*;
+use log::{error, info};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    env_logger::init();
+
+    let settings = match settings::load() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let traces = telemetry::from_settings(&settings).expect("Failed to set up trace export");
+    logging::from_settings(
+        &settings,
+        traces
+            .clone()
+            .map(|e| e as Arc<dyn telemetry::SpanExporter>),
+    )
+    .expect("Failed to set up logging");
+
+    let pool = database::from_settings(&settings).expect("Failed to connect to the database");
+    database::migrations::from_settings(&settings).expect("Failed to migrate the database");
+    let read_pool = database::replica_from_settings(&settings, &pool)
+        .expect("Failed to connect to the database replica");
+    let templates = templates::from_settings(&settings).expect("Failed to load templates");
+    let _templates_watcher = templates::watcher_from_settings(&settings, &templates)
+        .expect("Failed to watch the templates");
+    let locales = i18n::from_settings(&settings).expect("Failed to load locales");
+    let urls = urls::from_settings(&settings);
+    let session_keys = database::cache::from_settings(&settings);
+    let session_cookie = middlewares::session::from_settings(&settings)
+        .expect("Failed to configure the session cookie");
+    let postman = mails::Postman::new(
+        mails::Sender::from_settings(&settings).expect("Failed to configure mail sender"),
+        mails::transport::from_settings(&settings).expect("Failed to configure mail transport"),
+        mails::dkim::from_settings(&settings).expect("Failed to configure DKIM signing"),
+    )
+    .with_spool(
+        mails::transport::spool_from_settings(&settings).expect("Failed to open the mail spool"),
+    );
+    let postman_shutdown = postman.shutdown();
+    let postman = postman.start();
+    let db = pool.clone();
+    let mailbox = postman.clone();
+
+    let listeners = server::listeners(&settings.listen).expect("Invalid listen addresses");
+    let tls = server::tls_from_settings(&settings).expect("Failed to load TLS certificate");
+    let health_allowlist = middlewares::allowlist::IpAllowlist::parse(&settings.health_allowed_ips)
+        .expect("Invalid health_allowed_ips");
+    let metrics_allowlist =
+        middlewares::allowlist::IpAllowlist::parse(&settings.metrics_allowed_ips)
+            .expect("Invalid metrics_allowed_ips");
+    let server_settings = settings.clone();
+    let state = app::AppState {
+        repository: Arc::new(database::repository::Diesel::new(
+            pool.clone(),
+            read_pool.clone(),
+            session_keys.clone(),
+        )),
+        session_keys,
+        pool,
+        read_pool,
+        postman,
+        templates,
+        locales,
+        urls,
+        session_cookie,
+        health_allowlist,
+        metrics_allowlist,
+        settings,
+    };
+
+    let mut server = HttpServer::new(move || app::app(&state))
+        .keep_alive(server_settings.keep_alive)
+        .client_timeout(server_settings.client_timeout)
+        .client_shutdown(server_settings.client_shutdown)
+        .shutdown_timeout(server_settings.shutdown_timeout);
+    if let Some(workers) = server_settings.workers {
+        server = server.workers(workers);
+    }
+
+    let mut sockets = Vec::new();
+    for listener in listeners {
+        server = match (listener, &tls) {
+            (server::Listener::Tcp(address), Some(tls)) => {
+                server.bind_rustls(address, tls.clone())?
+            }
+            (server::Listener::Tcp(address), None) => server.bind(address)?,
+            (server::Listener::Unix(path), _) => {
+                sockets.push(format!("unix:{}", path.display()));
+                server.bind_uds(path)?
+            }
+        };
+    }
+    let endpoints = server
+        .addrs_with_scheme()
+        .into_iter()
+        .map(|(address, scheme)| format!("{}://{}", scheme, address))
+        .chain(sockets);
 
     // ASCII art banner always looks cool
     // https://www.patorjk.com/software/taag/#p=display&h=0&v=0&f=Bloody&t=Skeleton
@@ -48,70 +152,43 @@ async fn main() -> std::io::Result<()> {
                                                                      
                                                                  
         VERSION : DEV 0.0.1     
-        Your server is up and running at http://127.0.0.1:8080\n
-    "
+        Your server is up and running at :"
     );
+    for endpoint in endpoints {
+        println!("          - {}", endpoint);
+    }
+    println!();
+
+    // on SIGINT or SIGTERM, stops accepting connections
+    // and waits up to shutdown_timeout for the in-flight requests
+    server.run().await?;
+
+    info!("Server stopped, flushing the mail queue");
+    let stopping = Instant::now();
+    let mails = postman_shutdown
+        .run(
+            &mailbox,
+            Duration::from_secs(server_settings.mail_shutdown_timeout),
+        )
+        .await;
+
+    info!(
+        "{} database connections left, closed on exit",
+        db.state().connections
+    );
+
+    if let Some(traces) = traces {
+        info!("Exporting the last traces");
+        telemetry::SpanExporter::flush(traces.as_ref());
+    }
 
-    let pool = database::init_pool().expect("Failed to create pool");
-    let postman = mails::Postman.start();
-
-    HttpServer::new(move || {
-        App::new()
-            // add the pool to app state
-            .data(pool.clone())
-            .data(postman.clone())
-            // PURE API
-            .service(
-                web::scope("/api/v1")
-                    // lock down routes with User Middleware
-                    .wrap(middlewares::session::BrancaSession(Level::User))
-                    // AUTH routes
-                    .route("/login", web::post().to(handler::user::login))
-                    .route("/logout", web::get().to(handler::user::logout))
-                    // USER routes
-                    .service(
-                        web::scope("/user")
-                            .route("", web::get().to(handler::user::get))
-                            .route("/update", web::put().to(handler::user::update))
-                            .route("/delete", web::delete().to(handler::user::delete))
-                            .route("/register", web::post().to(handler::user::register))
-                            .route(
-                                "/forgot_password",
-                                web::post().to(handler::user::forgot_password),
-                            )
-                            .route(
-                                "/reset_password",
-                                web::post().to(handler::user::reset_password),
-                            )
-                            .route(
-                                "/change_password",
-                                web::post().to(handler::user::change_password),
-                            ),
-                    ),
-            )
-            // DASHBOARD
-            .service(
-                web::scope("/dashboard/")
-                    // lock down routes with Admin Middleware
-                    .wrap(middlewares::session::BrancaSession(Level::Admin))
-                    .route("login", web::get().to(handler::dashboard::dashboard_login))
-                    .service(
-                        Files::new(
-                            "",
-                            env::var("DASHBOARD_PATH").expect("DASHBOARD_PATH must be set"),
-                        )
-                        .index_file("index.html"),
-                    ),
-            )
-            // PUBLICS FILES
-            .service(Files::new(
-                "/",
-                env::var("PUBLIC_PATH").expect("PUBLIC_PATH must be set"),
-            ))
-            // enable logger - always register actix-web Logger middleware last
-            .wrap(middleware::Logger::default())
-    })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
+    match mails {
+        Ok(summary) => info!(
+            "Shutdown complete in {:?} : {}",
+            stopping.elapsed(),
+            summary
+        ),
+        Err(e) => error!("Shutdown complete, the mail queue was not flushed : {}", e),
+    }
+    Ok(())
 }
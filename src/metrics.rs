@@ -0,0 +1,321 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// Upper bounds of the histogram buckets, in seconds
+const BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+pub const HTTP_REQUESTS: Metric = Metric::counter(
+    "http_requests_total",
+    "HTTP requests, by method, route and status",
+);
+pub const HTTP_DURATION: Metric = Metric::histogram(
+    "http_request_duration_seconds",
+    "Time spent answering HTTP requests, by method, route and status",
+);
+pub const DB_POOL_CONNECTIONS: Metric = Metric::gauge(
+    "db_pool_connections",
+    "Connections opened by the database pool",
+);
+pub const DB_POOL_IDLE: Metric = Metric::gauge(
+    "db_pool_idle_connections",
+    "Connections of the database pool waiting to be used",
+);
+pub const DB_POOL_WAIT: Metric = Metric::histogram(
+    "db_pool_wait_seconds",
+    "Time spent waiting for a database connection",
+);
+pub const DB_POOL_TIMEOUTS: Metric = Metric::counter(
+    "db_pool_timeouts_total",
+    "Database connections not obtained in time",
+);
+pub const LOGINS: Metric =
+    Metric::counter("auth_logins_total", "Login attempts, by source and result");
+pub const TOKEN_FAILURES: Metric = Metric::counter(
+    "auth_token_failures_total",
+    "Session tokens refused, by reason",
+);
+pub const SESSION_CACHE: Metric = Metric::counter(
+    "session_cache_requests_total",
+    "Session key lookups in the cache, by result (hit or miss)",
+);
+pub const MAIL_QUEUED: Metric =
+    Metric::counter("mail_queued_total", "Mails accepted by the postman");
+pub const MAIL_SENT: Metric = Metric::counter("mail_sent_total", "Mails delivered");
+pub const MAIL_FAILED: Metric = Metric::counter("mail_failed_total", "Mails not delivered");
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+/// A metric family, its series are created on first use
+#[derive(Debug, Clone, Copy)]
+pub struct Metric {
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+}
+
+/// An `f64` updated without lock, stored as its bits
+#[derive(Default)]
+struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+    fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    fn add(&self, value: f64) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+    }
+}
+
+enum Series {
+    Value(AtomicF64),
+    Histogram {
+        buckets: Vec<AtomicU64>,
+        sum: AtomicF64,
+        count: AtomicU64,
+    },
+}
+
+struct Family {
+    metric: Metric,
+    series: BTreeMap<String, Arc<Series>>,
+}
+
+/// Every metric of the process, as the Prometheus clients do.
+/// Existing series are updated under the read lock, only new ones take the write lock.
+static REGISTRY: RwLock<BTreeMap<&str, Family>> = RwLock::new(BTreeMap::new());
+
+impl Metric {
+    const fn counter(name: &'static str, help: &'static str) -> Self {
+        Metric {
+            name,
+            help,
+            kind: Kind::Counter,
+        }
+    }
+
+    const fn gauge(name: &'static str, help: &'static str) -> Self {
+        Metric {
+            name,
+            help,
+            kind: Kind::Gauge,
+        }
+    }
+
+    const fn histogram(name: &'static str, help: &'static str) -> Self {
+        Metric {
+            name,
+            help,
+            kind: Kind::Histogram,
+        }
+    }
+
+    pub fn inc(&self, labels: &[(&str, &str)]) {
+        if let Series::Value(value) = &*self.series(labels) {
+            value.add(1.0);
+        }
+    }
+
+    pub fn set(&self, labels: &[(&str, &str)], new: f64) {
+        if let Series::Value(value) = &*self.series(labels) {
+            value.set(new);
+        }
+    }
+
+    pub fn observe(&self, labels: &[(&str, &str)], value: f64) {
+        if let Series::Histogram {
+            buckets,
+            sum,
+            count,
+        } = &*self.series(labels)
+        {
+            for (bucket, bound) in buckets.iter().zip(BUCKETS) {
+                if value <= *bound {
+                    bucket.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            sum.add(value);
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The series of `labels`, created on first use
+    fn series(&self, labels: &[(&str, &str)]) -> Arc<Series> {
+        let labels = render_labels(labels);
+        // metrics must never fail a request, a poisoned registry is still usable
+        let existing = REGISTRY
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(self.name)
+            .and_then(|family| family.series.get(&labels).cloned());
+        if let Some(series) = existing {
+            return series;
+        }
+
+        let mut registry = REGISTRY.write().unwrap_or_else(|e| e.into_inner());
+        let family = registry.entry(self.name).or_insert_with(|| Family {
+            metric: *self,
+            series: BTreeMap::new(),
+        });
+        family
+            .series
+            .entry(labels)
+            .or_insert_with(|| {
+                Arc::new(match self.kind {
+                    Kind::Histogram => Series::Histogram {
+                        buckets: BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+                        sum: AtomicF64::default(),
+                        count: AtomicU64::new(0),
+                    },
+                    _ => Series::Value(AtomicF64::default()),
+                })
+            })
+            .clone()
+    }
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// `name{labels}`, merging the extra `le` label of the histogram buckets
+fn series_name(name: &str, labels: &str, extra: Option<String>) -> String {
+    let labels = match (labels.is_empty(), extra) {
+        (true, None) => return name.to_owned(),
+        (true, Some(extra)) => extra,
+        (false, None) => labels.to_owned(),
+        (false, Some(extra)) => format!("{},{}", labels, extra),
+    };
+    format!("{}{{{}}}", name, labels)
+}
+
+/// The `method` label of a request : custom methods would create a series each
+pub fn method_label(method: &actix_web::http::Method) -> &'static str {
+    use actix_web::http::Method;
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::PATCH => "PATCH",
+        Method::OPTIONS => "OPTIONS",
+        Method::CONNECT => "CONNECT",
+        Method::TRACE => "TRACE",
+        _ => "other",
+    }
+}
+
+/// The `result` label of an operation
+pub fn outcome<T, E>(result: &Result<T, E>) -> &'static str {
+    match result {
+        Ok(_) => "success",
+        Err(_) => "failure",
+    }
+}
+
+/// Every metric in the Prometheus text format
+pub fn render() -> String {
+    let registry = REGISTRY.read().unwrap_or_else(|e| e.into_inner());
+    let mut text = String::new();
+
+    for family in registry.values() {
+        let metric = family.metric;
+        let kind = match metric.kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+        };
+        let _ = writeln!(text, "# HELP {} {}", metric.name, metric.help);
+        let _ = writeln!(text, "# TYPE {} {}", metric.name, kind);
+
+        for (labels, series) in &family.series {
+            match &**series {
+                Series::Value(value) => {
+                    let name = series_name(metric.name, labels, None);
+                    let _ = writeln!(text, "{} {}", name, value.get());
+                }
+                Series::Histogram {
+                    buckets,
+                    sum,
+                    count,
+                } => {
+                    let bucket = format!("{}_bucket", metric.name);
+                    // read the count first, so that no bucket exceeds it
+                    let count = count.load(Ordering::Relaxed);
+                    for (observed, bound) in buckets.iter().zip(BUCKETS) {
+                        let le = Some(format!("le=\"{}\"", bound));
+                        let observed = observed.load(Ordering::Relaxed).min(count);
+                        let _ = writeln!(text, "{} {}", series_name(&bucket, labels, le), observed);
+                    }
+                    let le = Some("le=\"+Inf\"".to_owned());
+                    let _ = writeln!(text, "{} {}", series_name(&bucket, labels, le), count);
+                    let sum_name = format!("{}_sum", metric.name);
+                    let _ = writeln!(
+                        text,
+                        "{} {}",
+                        series_name(&sum_name, labels, None),
+                        sum.get()
+                    );
+                    let count_name = format!("{}_count", metric.name);
+                    let _ = writeln!(text, "{} {}", series_name(&count_name, labels, None), count);
+                }
+            }
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_rendered_in_text_format() {
+        const HITS: Metric = Metric::counter("test_hits_total", "Hits");
+        const LATENCY: Metric = Metric::histogram("test_latency_seconds", "Latency");
+
+        HITS.inc(&[("route", "/a\"b")]);
+        HITS.inc(&[("route", "/a\"b")]);
+        LATENCY.observe(&[], 0.02);
+        LATENCY.observe(&[], 3.0);
+
+        let text = render();
+        assert!(text.contains("# TYPE test_hits_total counter\n"));
+        assert!(text.contains("test_hits_total{route=\"/a\\\"b\"} 2\n"));
+        assert!(text.contains("# TYPE test_latency_seconds histogram\n"));
+        assert!(text.contains("test_latency_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(text.contains("test_latency_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(text.contains("test_latency_seconds_bucket{le=\"5\"} 2\n"));
+        assert!(text.contains("test_latency_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("test_latency_seconds_sum 3.02\n"));
+        assert!(text.contains("test_latency_seconds_count 2\n"));
+    }
+}
@@ -0,0 +1,150 @@
+use actix_service::{Service, Transform};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error, HttpResponse};
+use futures::future::{ok, Either, Ready};
+use std::net::IpAddr;
+use std::task::{Context, Poll};
+
+use crate::errors::{ApiError, ErrorResponse};
+
+/// Restricts a scope to some networks, from a comma separated list
+/// of addresses (`10.0.0.1`) and CIDR blocks (`10.0.0.0/8`, `fd00::/8`).
+///
+/// The peer address is used, not `X-Forwarded-For` which clients can forge.
+/// An empty list lets everybody in.
+#[derive(Debug, Clone, Default)]
+pub struct IpAllowlist {
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl IpAllowlist {
+    pub fn parse(list: &str) -> Result<Self, ApiError> {
+        let networks = list
+            .split(',')
+            .map(str::trim)
+            .filter(|network| !network.is_empty())
+            .map(|network| {
+                let invalid = || ApiError::InternalError(format!("Invalid network : {}", network));
+                let (address, prefix) = match network.split_once('/') {
+                    Some((address, prefix)) => (address, Some(prefix)),
+                    None => (network, None),
+                };
+                let address: IpAddr = address.parse().map_err(|_| invalid())?;
+                let max = if address.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+                    None => max,
+                };
+                match prefix <= max {
+                    true => Ok((address, prefix)),
+                    _ => Err(invalid()),
+                }
+            })
+            .collect::<Result<Vec<_>, ApiError>>()?;
+        Ok(IpAllowlist { networks })
+    }
+
+    pub fn allows(&self, ip: Option<IpAddr>) -> bool {
+        if self.networks.is_empty() {
+            return true;
+        }
+        let ip = match ip {
+            Some(ip) => bits(ip),
+            None => return false,
+        };
+        self.networks.iter().any(|(network, prefix)| {
+            let network = bits(*network);
+            let mask = match *prefix {
+                0 => 0,
+                prefix => u128::MAX << (128 - u32::from(prefix)),
+            };
+            ip.0 == network.0 && (ip.1 ^ network.1) & mask == 0
+        })
+    }
+}
+
+/// An address as a family and left aligned bits, so prefixes mask the same way
+fn bits(ip: IpAddr) -> (bool, u128) {
+    match ip {
+        IpAddr::V4(ip) => (true, u128::from(u32::from(ip)) << 96),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => bits(IpAddr::V4(ip)),
+            None => (false, u128::from(ip)),
+        },
+    }
+}
+
+impl<S, B> Transform<S> for IpAllowlist
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = IpAllowlistMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(IpAllowlistMiddleware {
+            service,
+            allowlist: self.clone(),
+        })
+    }
+}
+
+pub struct IpAllowlistMiddleware<S> {
+    service: S,
+    allowlist: IpAllowlist,
+}
+
+impl<S, B> Service for IpAllowlistMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        match self.allowlist.allows(req.peer_addr().map(|addr| addr.ip())) {
+            true => Either::Left(self.service.call(req)),
+            _ => Either::Right(ok(req.into_response(
+                HttpResponse::Forbidden()
+                    .json::<ErrorResponse>((&"Address not allowed".to_owned()).into())
+                    .into_body(),
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn networks_are_matched() {
+        let ip = |ip: &str| Some(ip.parse().unwrap());
+        let allowlist = IpAllowlist::parse("127.0.0.1, 10.0.0.0/8, fd00::/8").unwrap();
+
+        assert!(allowlist.allows(ip("127.0.0.1")));
+        assert!(allowlist.allows(ip("::ffff:127.0.0.1")));
+        assert!(allowlist.allows(ip("10.20.30.40")));
+        assert!(allowlist.allows(ip("fd12::1")));
+        assert!(!allowlist.allows(ip("127.0.0.2")));
+        assert!(!allowlist.allows(ip("11.0.0.1")));
+        assert!(!allowlist.allows(ip("fe80::1")));
+        assert!(!allowlist.allows(None));
+
+        assert!(IpAllowlist::parse("").unwrap().allows(None));
+        assert!(IpAllowlist::parse("10.0.0.0/33").is_err());
+        assert!(IpAllowlist::parse("localhost").is_err());
+    }
+}
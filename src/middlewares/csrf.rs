@@ -0,0 +1,282 @@
+use actix_service::{Service, Transform};
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::PayloadError,
+    http::Method,
+    web::{self, Bytes, BytesMut},
+    Error, FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
+use futures::future::{ok, ready, LocalBoxFuture, Ready};
+use futures::{stream, StreamExt};
+use rand::{distributions::Alphanumeric, Rng};
+use ring::constant_time::verify_slices_are_equal;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use super::session::SessionCookie;
+use crate::errors::{ApiError, ErrorResponse};
+
+/// Cookie holding the token of the browser, readable by scripts so they can send it back
+pub const COOKIE: &str = "CsrfToken";
+/// Header api clients submit the token in
+pub const HEADER: &str = "X-CSRF-Token";
+/// Hidden field html forms submit the token in
+pub const FIELD: &str = "csrf_token";
+/// Largest form body read to find the token
+const FORM_LIMIT: usize = 64 * 1024;
+
+/// Middleware protecting cookie-authenticated requests against cross-site request forgery.
+///
+/// Uses double-submit tokens : every browser gets a random token in the `CsrfToken` cookie,
+/// and unsafe requests must send it back in the `X-CSRF-Token` header or the `csrf_token`
+/// form field. Safe methods and api calls without a session cookie are let through, whatever
+/// their `Authorization` header : a forged request carries the cookie along with any header.
+/// The token of the request is available to handlers through the [`CsrfToken`] extractor.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Csrf;
+
+impl<S, B> Transform<S> for Csrf
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CsrfMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CsrfMiddleware {
+            service: Rc::new(RefCell::new(service)),
+        })
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for CsrfMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let (token, cookie) = token(&req);
+            req.extensions_mut().insert(CsrfToken(token.clone()));
+
+            if must_check(&req) {
+                let submitted = match req.headers().get(HEADER).and_then(|h| h.to_str().ok()) {
+                    Some(submitted) => Some(submitted.to_owned()),
+                    None if is_form(&req) => form_token(&mut req).await?,
+                    None => None,
+                };
+                // a fresh cookie means the browser had no token to submit
+                let valid = cookie.is_none()
+                    && submitted.is_some_and(|submitted| verify(&token, &submitted).is_ok());
+                if !valid {
+                    return Ok(req.into_response(
+                        HttpResponse::Forbidden()
+                            .json::<ErrorResponse>((&"Invalid CSRF token".to_owned()).into())
+                            .into_body(),
+                    ));
+                }
+            }
+
+            let future = service.borrow_mut().call(req);
+            let mut res = future.await?;
+            if let Some(cookie) = cookie {
+                res.response_mut().add_cookie(&cookie)?;
+            }
+            Ok(res)
+        })
+    }
+}
+
+/// The CSRF token of the request, to put in the forms of rendered pages
+#[derive(Debug, Clone)]
+pub struct CsrfToken(pub String);
+
+impl FromRequest for CsrfToken {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<CsrfToken>().cloned().ok_or_else(|| {
+            ApiError::InternalError("The CSRF middleware is not registered".to_owned())
+        }))
+    }
+}
+
+/// Only unsafe requests authenticated by the browser itself need a token,
+/// along with forms that could log a victim in (login CSRF)
+fn must_check(req: &ServiceRequest) -> bool {
+    let safe = matches!(
+        *req.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    );
+    let session = req
+        .app_data::<web::Data<SessionCookie>>()
+        .map(|cookie| cookie.get_ref().clone())
+        .unwrap_or_default()
+        .value(req)
+        .is_some();
+    !safe && (session || is_form(req))
+}
+
+fn is_form(req: &ServiceRequest) -> bool {
+    req.content_type() == "application/x-www-form-urlencoded"
+}
+
+/// Read the token field of a form body, then put the body back for the handler
+async fn form_token(req: &mut ServiceRequest) -> Result<Option<String>, Error> {
+    let mut payload = req.take_payload();
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > FORM_LIMIT {
+            return Ok(None);
+        }
+    }
+    let body = body.freeze();
+    let fields: HashMap<String, String> = serde_urlencoded::from_bytes(&body).unwrap_or_default();
+
+    let chunk: Result<Bytes, PayloadError> = Ok(body);
+    req.set_payload(Payload::Stream(Box::pin(stream::once(ready(chunk)))));
+    Ok(fields.get(FIELD).cloned())
+}
+
+/// Token of the request, with the cookie to set when the browser has none yet
+fn token<R: HttpMessage>(req: &R) -> (String, Option<Cookie<'static>>) {
+    match req.cookie(COOKIE) {
+        Some(cookie) if !cookie.value().is_empty() => (cookie.value().to_owned(), None),
+        _ => {
+            let token = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(32)
+                .collect::<String>();
+            let cookie = Cookie::build(COOKIE, token.clone())
+                .path("/")
+                .same_site(SameSite::Strict)
+                .finish();
+            (token, Some(cookie))
+        }
+    }
+}
+
+fn verify(token: &str, submitted: &str) -> Result<(), ApiError> {
+    verify_slices_are_equal(token.as_bytes(), submitted.as_bytes())
+        .map_err(|_| ApiError::InternalError("Invalid CSRF token".to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        http::{header, StatusCode},
+        test, App,
+    };
+
+    async fn echo(csrf: CsrfToken, body: String) -> HttpResponse {
+        HttpResponse::Ok().body(format!("{}|{}", csrf.0, body))
+    }
+
+    #[actix_rt::test]
+    async fn unsafe_requests_need_the_token() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(Csrf)
+                .route("/", web::get().to(echo))
+                .route("/", web::post().to(echo)),
+        )
+        .await;
+
+        // safe requests get a token
+        let res = test::call_service(&mut app, test::TestRequest::get().to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let cookie = res
+            .response()
+            .cookies()
+            .find(|c| c.name() == COOKIE)
+            .unwrap()
+            .into_owned();
+        let token = cookie.value().to_owned();
+
+        let session = SessionCookie::default().build("session".to_owned());
+        let post = || {
+            test::TestRequest::post()
+                .cookie(cookie.clone())
+                .cookie(session.clone())
+        };
+        macro_rules! status {
+            ($req:expr) => {
+                test::call_service(&mut app, $req.to_request())
+                    .await
+                    .status()
+            };
+        }
+        assert_eq!(status!(post()), StatusCode::FORBIDDEN);
+        assert_eq!(
+            status!(post().header(HEADER, "forged")),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status!(test::TestRequest::post()
+                .cookie(session.clone())
+                .header(HEADER, token.as_str())),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(status!(test::TestRequest::post()), StatusCode::OK);
+        assert_eq!(
+            status!(post().header(HEADER, token.as_str())),
+            StatusCode::OK
+        );
+        // a bearer header doesn't spare a request carrying the session cookie
+        assert_eq!(
+            status!(test::TestRequest::post()
+                .cookie(session.clone())
+                .header(header::AUTHORIZATION, "Bearer abc")),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status!(test::TestRequest::post().header(header::AUTHORIZATION, "Bearer abc")),
+            StatusCode::OK
+        );
+
+        // forms submit the token in a field, and the handler still gets the whole body
+        let form = format!("name=skeleton&{}={}", FIELD, token);
+        let res = test::call_service(
+            &mut app,
+            post()
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .set_payload(form.clone())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            test::read_body(res).await,
+            Bytes::from(format!("{}|{}", token, form))
+        );
+    }
+}
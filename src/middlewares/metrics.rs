@@ -0,0 +1,120 @@
+use actix_service::{Service, Transform};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use crate::metrics::{method_label, HTTP_DURATION, HTTP_REQUESTS};
+
+/// Middleware counting and timing the requests, by method, route pattern and status.
+///
+/// The route is the pattern the request matched (`/api/v1/user/{id}`), so
+/// ids don't create new series, and `unmatched` when no route matched.
+/// Likewise, methods other than the standard ones are counted as `other`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S> for RequestMetrics
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestMetricsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestMetricsMiddleware { service })
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestMetricsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let method = method_label(req.method());
+        let future = self.service.call(req);
+
+        Box::pin(async move {
+            let result = future.await;
+            let (route, status) = match &result {
+                Ok(res) => (
+                    res.request().match_pattern(),
+                    res.status().as_u16().to_string(),
+                ),
+                Err(e) => (
+                    None,
+                    e.as_response_error().status_code().as_u16().to_string(),
+                ),
+            };
+            let route = route.unwrap_or_else(|| "unmatched".to_owned());
+            let labels = [
+                ("method", method),
+                ("route", route.as_str()),
+                ("status", status.as_str()),
+            ];
+            HTTP_REQUESTS.inc(&labels);
+            HTTP_DURATION.observe(&labels, started.elapsed().as_secs_f64());
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[actix_rt::test]
+    async fn requests_are_counted_by_route_pattern() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(RequestMetrics)
+                .route("/metrics-test/{id}", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for path in &["/metrics-test/1", "/metrics-test/2", "/metrics-unknown"] {
+            test::call_service(&mut app, test::TestRequest::get().uri(path).to_request()).await;
+        }
+        let custom = actix_web::http::Method::from_bytes(b"METRICS-TEST").unwrap();
+        test::call_service(
+            &mut app,
+            test::TestRequest::with_uri("/metrics-test/3")
+                .method(custom)
+                .to_request(),
+        )
+        .await;
+
+        let text = crate::metrics::render();
+        assert!(text.contains(
+            "http_requests_total{method=\"GET\",route=\"/metrics-test/{id}\",status=\"200\"} 2\n"
+        ));
+        assert!(text.contains(
+            "http_request_duration_seconds_count{method=\"GET\",route=\"/metrics-test/{id}\",status=\"200\"} 2\n"
+        ));
+        assert!(text.contains("route=\"unmatched\",status=\"404\""));
+        assert!(text.contains("method=\"other\""));
+        assert!(!text.contains("METRICS-TEST"));
+    }
+}
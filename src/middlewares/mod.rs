@@ -1 +1,5 @@
+pub mod allowlist;
+pub mod csrf;
+pub mod metrics;
+pub mod request_id;
 pub mod session;
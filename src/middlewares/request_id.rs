@@ -0,0 +1,155 @@
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::ServiceRequest,
+    dev::ServiceResponse,
+    http::header::{HeaderName, HeaderValue},
+    Error,
+};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tracing::{field::Empty, info, info_span, Instrument, Span};
+
+/// Header carrying the id of a request, from the client or a proxy, and in the response
+pub const HEADER: &str = "x-request-id";
+/// W3C trace context of the caller, continued by the request span
+const TRACEPARENT: &str = "traceparent";
+/// Longest id accepted from a client
+const MAX_LENGTH: usize = 128;
+
+/// Middleware giving every request an id and a `request` span, then logging its outcome.
+///
+/// The id comes from the `X-Request-Id` header when it is sane, a new uuid otherwise,
+/// and is echoed in the response. Everything logged while the request is handled,
+/// mails it queues included, carries the id, the route and the user id once known.
+/// Only the route pattern is logged, never the path or query which may hold tokens.
+/// The span continues the trace of a `traceparent` header, when the caller sent one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestId;
+
+impl<S, B> Transform<S> for RequestId
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestIdMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestIdMiddleware { service })
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: S,
+}
+
+/// The id sent by the client, when it is short and printable
+fn incoming(req: &ServiceRequest) -> Option<String> {
+    let id = req.headers().get(HEADER)?.to_str().ok()?;
+    let sane = !id.is_empty()
+        && id.len() <= MAX_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c));
+    match sane {
+        true => Some(id.to_owned()),
+        _ => None,
+    }
+}
+
+impl<S, B> Service for RequestIdMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let id = incoming(&req).unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        let span = info_span!(
+            "request",
+            otel.kind = "server",
+            otel.status_code = Empty,
+            traceparent = req.headers().get(TRACEPARENT).and_then(|v| v.to_str().ok()),
+            request_id = %id,
+            method = %req.method(),
+            route = Empty,
+            status = Empty,
+            latency_ms = Empty,
+            user_id = Empty,
+        );
+        // the inner middlewares start their work in `call`, so inside the span too
+        let future = span.in_scope(|| self.service.call(req));
+
+        Box::pin(
+            async move {
+                let mut result = future.await;
+                let (route, status) = match &result {
+                    Ok(res) => (res.request().match_pattern(), res.status().as_u16()),
+                    Err(e) => (None, e.as_response_error().status_code().as_u16()),
+                };
+                let span = Span::current();
+                span.record("route", route.as_deref().unwrap_or("unmatched"));
+                span.record("status", status);
+                span.record("latency_ms", started.elapsed().as_secs_f64() * 1000.0);
+                if status >= 500 {
+                    span.record("otel.status_code", "ERROR");
+                }
+                info!("request completed");
+
+                if let (Ok(res), Ok(value)) = (&mut result, HeaderValue::from_str(&id)) {
+                    res.headers_mut()
+                        .insert(HeaderName::from_static(HEADER), value);
+                }
+                result
+            }
+            .instrument(span),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[actix_rt::test]
+    async fn request_id_is_echoed_or_generated() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(RequestId)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .header(HEADER, "proxy-1234")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.headers().get(HEADER).unwrap(), "proxy-1234");
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .header(HEADER, "<script>")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        let generated = res.headers().get(HEADER).unwrap().to_str().unwrap();
+        assert!(uuid::Uuid::parse_str(generated).is_ok());
+    }
+}
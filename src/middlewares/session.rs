@@ -1,30 +1,166 @@
 use actix_service::{Service, Transform};
 use actix_web::{
-    dev::ServiceRequest, dev::ServiceResponse, http, web, Error, HttpMessage, HttpResponse,
+    cookie::{Cookie, SameSite},
+    dev::ServiceRequest,
+    dev::ServiceResponse,
+    http, web, Error, HttpMessage, HttpResponse,
 };
-use futures::future::{ok, Either, Ready};
+use derive_more::Display;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::task::{Context, Poll};
+use time::{Duration, OffsetDateTime};
+use tracing::{info_span, Instrument, Span};
 
-use crate::db;
+use crate::db::repository::{self, SessionRepository};
+use crate::db::{self, cache::SessionKeys, user::TokenError};
 use crate::errors::*;
+use crate::metrics::TOKEN_FAILURES;
+use crate::settings::Settings;
 
 /// Determines the behavior of the [`BrancaSession`] middleware.
 /// The default is `Level::User`.
 #[non_exhaustive]
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub enum Level {
     /// need a BrancaToken of User level
+    #[default]
     User,
     /// need a BrancaToken of Admin level
     Admin,
 }
 
-impl Default for Level {
+/// Attributes of the session cookie, applied by every handler setting or clearing it
+/// and used by the middlewares to find it.
+///
+/// The defaults are meant for production : a `__Host-` prefixed, `Secure`, `HttpOnly`,
+/// `SameSite=Lax` cookie living as long as its token.
+#[derive(Debug, Clone)]
+pub struct SessionCookie {
+    name: String,
+    domain: Option<String>,
+    path: String,
+    secure: bool,
+    same_site: SameSite,
+    ttl: u32,
+}
+
+impl Default for SessionCookie {
     fn default() -> Self {
-        Level::User
+        SessionCookie::new("BrancaToken", None, "/", true, SameSite::Lax, true, 86400)
+            .expect("Default session cookie is valid")
     }
 }
 
+impl SessionCookie {
+    /// `host_prefix` prepends `__Host-` to the name, which browsers only accept
+    /// on secure cookies without domain and with a `/` path.
+    pub fn new(
+        name: &str,
+        domain: Option<&str>,
+        path: &str,
+        secure: bool,
+        same_site: SameSite,
+        host_prefix: bool,
+        ttl: u32,
+    ) -> Result<Self, ApiError> {
+        if host_prefix && (!secure || domain.is_some() || path != "/") {
+            return Err(ApiError::InternalError(
+                "A __Host- session cookie must be secure, without domain and on the / path"
+                    .to_owned(),
+            ));
+        }
+        if same_site == SameSite::None && !secure {
+            return Err(ApiError::InternalError(
+                "A SameSite=None session cookie must be secure".to_owned(),
+            ));
+        }
+
+        Ok(SessionCookie {
+            name: match host_prefix {
+                true => format!("__Host-{}", name),
+                _ => name.to_owned(),
+            },
+            domain: domain.map(|domain| domain.to_owned()),
+            path: path.to_owned(),
+            secure,
+            same_site,
+            ttl,
+        })
+    }
+
+    /// Lifetime of the session tokens, in seconds
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    /// The cookie carrying a session token
+    pub fn build(&self, token: String) -> Cookie<'static> {
+        self.builder(token)
+            .max_age(Duration::seconds(self.ttl.into()))
+            .finish()
+    }
+
+    /// The cookie erasing the session of the browser
+    pub fn removal(&self) -> Cookie<'static> {
+        self.builder(String::new())
+            .max_age(Duration::zero())
+            .expires(OffsetDateTime::now_utc() - Duration::days(365))
+            .finish()
+    }
+
+    #[allow(dead_code)] // used by the tests
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Value of the session cookie of a request
+    pub fn value<R: HttpMessage>(&self, req: &R) -> Option<String> {
+        req.cookie(&self.name)
+            .map(|cookie| cookie.value().to_string())
+    }
+
+    fn builder(&self, value: String) -> actix_web::cookie::CookieBuilder<'static> {
+        let mut builder = Cookie::build(self.name.clone(), value)
+            .path(self.path.clone())
+            .secure(self.secure)
+            .http_only(true)
+            .same_site(self.same_site);
+        if let Some(domain) = &self.domain {
+            builder = builder.domain(domain.clone());
+        }
+        builder
+    }
+}
+
+/// Build the session cookie from the `session_cookie_*` and `session_ttl` settings.
+/// Development over plain http needs `session_cookie_secure` and
+/// `session_cookie_host_prefix` set to false.
+pub fn from_settings(settings: &Settings) -> Result<SessionCookie, ApiError> {
+    let same_site = match settings.session_cookie_samesite.to_lowercase().as_ref() {
+        "strict" => SameSite::Strict,
+        "lax" => SameSite::Lax,
+        "none" => SameSite::None,
+        other => {
+            return Err(ApiError::InternalError(format!(
+                "Invalid session_cookie_samesite : {}",
+                other
+            )))
+        }
+    };
+
+    SessionCookie::new(
+        &settings.session_cookie_name,
+        settings.session_cookie_domain.as_deref(),
+        &settings.session_cookie_path,
+        settings.session_cookie_secure,
+        same_site,
+        settings.session_cookie_host_prefix,
+        settings.session_ttl,
+    )
+}
+
 /// Middleware to restrain access to leveled users.
 /// Possibles values Level::User, Level::Admin
 #[derive(Debug, Clone, Copy, Default)]
@@ -36,6 +172,7 @@ pub struct BrancaSession(pub Level);
 impl<S, B> Transform<S> for BrancaSession
 where
     S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -48,48 +185,112 @@ where
 
     fn new_transform(&self, service: S) -> Self::Future {
         ok(BrancaSessionMiddleware {
-            service,
+            service: Rc::new(RefCell::new(service)),
             level: self.0,
         })
     }
 }
 
 pub struct BrancaSessionMiddleware<S> {
-    service: S,
+    // shared with the futures, which call it once the token is verified
+    service: Rc<RefCell<S>>,
     level: Level,
 }
 
 impl<S, B> Service for BrancaSessionMiddleware<S>
 where
     S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: 'static,
     S::Future: 'static,
-    //B: 'static,
+    B: 'static,
 {
     type Request = ServiceRequest;
     type Response = ServiceResponse<B>;
     type Error = Error;
-    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.service.poll_ready(cx)
+        self.service.borrow_mut().poll_ready(cx)
     }
 
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
-        match is_authorized(&req, self.level) {
-            Ok(()) => Either::Left(self.service.call(req)),
-            Err(e) => Either::Right(ok(req.into_response(
-                HttpResponse::Found()
-                    .header(
-                        http::header::LOCATION,
-                        match self.level {
-                            Level::Admin => "/dashboard/login",
-                            Level::User => "/api/v1/login",
-                        },
-                    )
-                    .body(format!("Invalid Token : {}", e))
-                    .into_body(),
-            ))),
+        let service = self.service.clone();
+        let level = self.level;
+
+        Box::pin(async move {
+            match is_authorized(&req, level).await {
+                Ok(()) => {
+                    let future = service.borrow_mut().call(req);
+                    future.await
+                }
+                Err(denied) => {
+                    let res = refusal(&req, level, denied);
+                    Ok(req.into_response(res.into_body()))
+                }
+            }
+        })
+    }
+}
+
+/// Why the middleware refuses a request
+#[derive(Debug, Display)]
+enum Denied {
+    /// no valid session
+    Unauthenticated(ApiError),
+    /// a valid session, without the level required
+    Forbidden(ApiError),
+    /// the session could not be checked, the client should retry rather than log in again
+    Unavailable(ApiError),
+}
+
+/// Browsers navigating the dashboard without a session are sent to its login page, which brings them
+/// back to the page they asked for through `next`. Everybody else gets a JSON error :
+/// 401 without a valid session, 403 without the level, 503 when it can't be checked.
+fn refusal(req: &ServiceRequest, level: Level, denied: Denied) -> HttpResponse {
+    let html = req
+        .headers()
+        .get(http::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+
+    match denied {
+        Denied::Unavailable(e) => HttpResponse::ServiceUnavailable().json(ErrorResponse::from(&e)),
+        // logging in again would not make a user an admin, the 403 stays
+        Denied::Unauthenticated(_) if level == Level::Admin && html => {
+            // only a page can be asked again after the login, not a form submission
+            let location = match (
+                req.method() == http::Method::GET,
+                req.uri().path_and_query(),
+            ) {
+                (true, Some(page)) => serde_urlencoded::to_string([("next", page.as_str())])
+                    .map(|query| format!("/dashboard/login?{}", query))
+                    .unwrap_or_else(|_| "/dashboard/login".to_owned()),
+                _ => "/dashboard/login".to_owned(),
+            };
+            HttpResponse::Found()
+                .header(http::header::LOCATION, location)
+                .finish()
+        }
+        Denied::Unauthenticated(e) => {
+            let cookie = req
+                .app_data::<web::Data<SessionCookie>>()
+                .map(|cookie| cookie.get_ref().clone())
+                .unwrap_or_default();
+            let realm = match level {
+                Level::Admin => "dashboard",
+                Level::User => "api",
+            };
+            HttpResponse::Unauthorized()
+                .header(
+                    http::header::WWW_AUTHENTICATE,
+                    format!(
+                        "Cookie realm=\"{}\", cookie-name=\"{}\"",
+                        realm, cookie.name
+                    ),
+                )
+                .json(ErrorResponse::from(&e))
         }
+        Denied::Forbidden(e) => HttpResponse::Forbidden().json(ErrorResponse::from(&e)),
     }
 }
 
@@ -99,8 +300,9 @@ struct JsonBrancaToken {
     token: String,
 }
 
-/// Will check if the user is allowed to process
-fn is_authorized(req: &ServiceRequest, level: Level) -> Result<(), ApiError> {
+/// Will check if the user is allowed to process.
+/// The key of the user comes from the cache, or from the session repository on the blocking pool.
+async fn is_authorized(req: &ServiceRequest, level: Level) -> Result<(), Denied> {
     match level {
         Level::Admin if req.path() == "/dashboard/login" => return Ok(()),
         Level::User
@@ -114,21 +316,262 @@ fn is_authorized(req: &ServiceRequest, level: Level) -> Result<(), ApiError> {
         _ => (),
     };
 
-    let t = extract_cookie_token(&req)?;
-    let pool = req
-        .app_data::<web::Data<db::DbPool>>()
-        .unwrap()
-        .get()
-        .unwrap();
+    let cookie = req
+        .app_data::<web::Data<SessionCookie>>()
+        .map(|cookie| cookie.get_ref().clone())
+        .unwrap_or_default();
+    let value = cookie.value(req).ok_or_else(|| {
+        rejected(
+            "missing",
+            ApiError::InternalError("MissingToken".to_owned()),
+        )
+    })?;
+    let t: JsonBrancaToken =
+        serde_json::from_str(&value).map_err(|e| rejected("malformed", e.into()))?;
+    let sessions = req
+        .app_data::<web::Data<dyn SessionRepository>>()
+        .ok_or_else(|| {
+            Denied::Unavailable(ApiError::InternalError("No session repository".to_owned()))
+        })?;
 
-    db::user::verify_token(&t.id, &t.token, level == Level::Admin, &pool)
+    let id = t.id;
+    let span = info_span!("session.verify", admin = level == Level::Admin);
+    let cache = req.app_data::<web::Data<SessionKeys>>();
+    let key = match cache.and_then(|cache| cache.get(id)) {
+        Some(key) => key,
+        None => {
+            // before the read, an invalidation during it keeps the key out of the cache
+            let generation = cache.map(|cache| cache.generation());
+            let key = repository::run(sessions, move |sessions| sessions.session_key(id))
+                .instrument(span.clone())
+                .await
+                .map_err(Denied::Unavailable)?
+                .ok_or_else(|| rejected("unknown_user", TokenError::UnknownUser.into()))?;
+            if let (Some(cache), Some(generation)) = (cache, generation) {
+                cache.insert(id, key.clone(), generation);
+            }
+            key
+        }
+    };
+    span.in_scope(|| db::user::verify_token(&key, &t.token, level == Level::Admin, cookie.ttl()))
+        .map_err(|e| match e {
+            TokenError::NotAdmin => Denied::Forbidden(rejected(e.reason(), e.into())),
+            e => rejected(e.reason(), e.into()).into(),
+        })?;
+    Span::current().record("user_id", id);
+    Ok(())
 }
 
-/// Will extract the token from a cookie that was set previously.
-fn extract_cookie_token(req: &ServiceRequest) -> Result<JsonBrancaToken, ApiError> {
-    Ok(serde_json::from_str(
-        &req.cookie("BrancaToken")
-            .map(|cookie| cookie.value().to_string())
-            .ok_or(ApiError::InternalError("MissingToken".to_owned()))?,
-    )?)
+impl From<ApiError> for Denied {
+    fn from(error: ApiError) -> Self {
+        Denied::Unauthenticated(error)
+    }
+}
+
+/// Count the refused token, by reason
+fn rejected(reason: &str, error: ApiError) -> ApiError {
+    TOKEN_FAILURES.inc(&[("reason", reason)]);
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_cookie_attributes() {
+        let cookie = SessionCookie::default().build("token".to_owned());
+        assert_eq!(cookie.name(), "__Host-BrancaToken");
+        assert_eq!(cookie.path(), Some("/"));
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        assert_eq!(cookie.max_age(), Some(Duration::seconds(86400)));
+
+        let dev = SessionCookie::new(
+            "Session",
+            Some("skeleton.test"),
+            "/api",
+            false,
+            SameSite::Strict,
+            false,
+            60,
+        )
+        .unwrap();
+        let removal = dev.removal();
+        assert_eq!(removal.name(), "Session");
+        assert_eq!(removal.domain(), Some("skeleton.test"));
+        assert_eq!(removal.value(), "");
+        assert_eq!(removal.max_age(), Some(Duration::zero()));
+
+        // browsers would drop these cookies
+        assert!(SessionCookie::new("S", None, "/", false, SameSite::Lax, true, 60).is_err());
+        assert!(SessionCookie::new("S", None, "/api", true, SameSite::Lax, true, 60).is_err());
+        assert!(SessionCookie::new("S", None, "/", false, SameSite::None, false, 60).is_err());
+    }
+
+    use actix_web::test;
+    use std::sync::Arc;
+
+    /// nothing listens there, every checkout fails
+    fn unreachable_pool() -> db::DbPool {
+        db::DbPool::builder()
+            .connection_timeout(std::time::Duration::from_millis(100))
+            .build_unchecked(diesel::r2d2::ConnectionManager::new(
+                "postgres://127.0.0.1:1/skeleton",
+            ))
+    }
+
+    fn session_request(uri: &str, user: i32, token: &str) -> test::TestRequest {
+        let value = serde_json::json!({ "id": user, "token": token }).to_string();
+        test::TestRequest::get()
+            .uri(uri)
+            .cookie(Cookie::new("__Host-BrancaToken", value))
+    }
+
+    #[actix_rt::test]
+    async fn cached_keys_spare_the_database() {
+        use crate::db::cache::SessionKey;
+        use actix_web::App;
+
+        let cache = web::Data::new(SessionKeys::new(10, std::time::Duration::from_secs(60)));
+        let mut app = test::init_service(
+            App::new()
+                .configure(repository::configure(Arc::new(repository::Diesel::new(
+                    unreachable_pool(),
+                    db::ReadPool(unreachable_pool()),
+                    cache.clone(),
+                ))))
+                .app_data(cache.clone())
+                .data(SessionCookie::default())
+                .service(
+                    web::scope("/api/v1")
+                        .wrap(BrancaSession(Level::User))
+                        .route("/user", web::get().to(HttpResponse::Ok)),
+                )
+                .service(
+                    web::scope("/dashboard/")
+                        .wrap(BrancaSession(Level::Admin))
+                        .route("users", web::get().to(HttpResponse::Ok)),
+                ),
+        )
+        .await;
+
+        let key = SessionKey {
+            token_key: "0123456789abcdef0123456789abcdef".to_owned(),
+            is_admin: false,
+            is_active: true,
+        };
+        let token = branca::Branca::new(key.token_key.as_bytes())
+            .unwrap()
+            .encode(b"now")
+            .unwrap();
+        cache.insert(4242, key, cache.generation());
+
+        let req = session_request("/api/v1/user", 4242, &token).to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), http::StatusCode::OK);
+
+        // a user but not an admin
+        let req = session_request("/dashboard/users", 4242, &token).to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), http::StatusCode::FORBIDDEN);
+
+        // not cached, the database is needed
+        let req = session_request("/api/v1/user", 4243, &token).to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_rt::test]
+    async fn refusals_depend_on_the_client() {
+        use crate::db::cache::SessionKey;
+        use actix_web::App;
+
+        let cache = web::Data::new(SessionKeys::new(10, std::time::Duration::from_secs(60)));
+        let mut app = test::init_service(
+            App::new()
+                .configure(repository::configure(Arc::new(repository::Diesel::new(
+                    unreachable_pool(),
+                    db::ReadPool(unreachable_pool()),
+                    cache.clone(),
+                ))))
+                .app_data(cache.clone())
+                .data(SessionCookie::default())
+                .service(
+                    web::scope("/api/v1")
+                        .wrap(BrancaSession(Level::User))
+                        .route("/user", web::get().to(HttpResponse::Ok)),
+                )
+                .service(
+                    web::scope("/dashboard/")
+                        .wrap(BrancaSession(Level::Admin))
+                        .route("users", web::get().to(HttpResponse::Ok))
+                        .route("users/{id}/promote", web::post().to(HttpResponse::Ok)),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/user")
+            .header(http::header::ACCEPT, "text/html")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            res.headers().get(http::header::WWW_AUTHENTICATE).unwrap(),
+            "Cookie realm=\"api\", cookie-name=\"__Host-BrancaToken\""
+        );
+        let body: ErrorResponse = test::read_body_json(res).await;
+        assert_eq!(
+            serde_json::to_value(body).unwrap()["errors"][0],
+            "MissingToken"
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/dashboard/users?search=bob")
+            .header(http::header::ACCEPT, "text/html,application/xhtml+xml")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), http::StatusCode::FOUND);
+        assert_eq!(
+            res.headers().get(http::header::LOCATION).unwrap(),
+            "/dashboard/login?next=%2Fdashboard%2Fusers%3Fsearch%3Dbob"
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/dashboard/users/1/promote")
+            .header(http::header::ACCEPT, "text/html")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(
+            res.headers().get(http::header::LOCATION).unwrap(),
+            "/dashboard/login"
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/dashboard/users")
+            .header(http::header::ACCEPT, "application/json")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+
+        // a user browsing the dashboard is not sent to log in again
+        let key = SessionKey {
+            token_key: "0123456789abcdef0123456789abcdef".to_owned(),
+            is_admin: false,
+            is_active: true,
+        };
+        let token = branca::Branca::new(key.token_key.as_bytes())
+            .unwrap()
+            .encode(b"now")
+            .unwrap();
+        cache.insert(4242, key, cache.generation());
+        let req = session_request("/dashboard/users", 4242, &token)
+            .header(http::header::ACCEPT, "text/html")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), http::StatusCode::FORBIDDEN);
+        assert!(res.headers().get(http::header::LOCATION).is_none());
+    }
 }
@@ -0,0 +1,171 @@
+use actix_web::rt::signal::unix::{signal, SignalKind};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{ClientHello, NoClientAuth, ResolvesServerCert, ServerConfig};
+
+use log::{error, info};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use crate::errors::ApiError;
+use crate::settings::Settings;
+
+/// An address the server listens on
+#[derive(Debug, Clone, PartialEq)]
+pub enum Listener {
+    /// `host:port`, served over TLS when a certificate is configured
+    Tcp(String),
+    /// `unix:/path/to.sock`, always plain http, meant for a local reverse proxy
+    Unix(PathBuf),
+}
+
+impl FromStr for Listener {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => Ok(Listener::Unix(PathBuf::from(path))),
+            None if s
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()) =>
+            {
+                Ok(Listener::Tcp(s.to_owned()))
+            }
+            _ => Err(ApiError::InternalError(format!(
+                "Invalid listen address : {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Parse a comma separated list of listen addresses
+pub fn listeners(listen: &str) -> Result<Vec<Listener>, ApiError> {
+    let listeners = listen
+        .split(',')
+        .filter(|address| !address.trim().is_empty())
+        .map(str::parse)
+        .collect::<Result<Vec<Listener>, ApiError>>()?;
+    match listeners.is_empty() {
+        true => Err(ApiError::InternalError(
+            "At least one listen address is required".to_owned(),
+        )),
+        _ => Ok(listeners),
+    }
+}
+
+/// A certificate and its key, read again from their files on demand
+/// so renewed certificates are served without restarting.
+pub struct ReloadableCert {
+    cert_path: String,
+    key_path: String,
+    current: RwLock<CertifiedKey>,
+}
+
+impl ReloadableCert {
+    pub fn load(cert_path: &str, key_path: &str) -> Result<Arc<Self>, ApiError> {
+        Ok(Arc::new(ReloadableCert {
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+            current: RwLock::new(read_certified_key(cert_path, key_path)?),
+        }))
+    }
+
+    /// Swap the served certificate, keeping the current one if the new files are invalid
+    pub fn reload(&self) -> Result<(), ApiError> {
+        let key = read_certified_key(&self.cert_path, &self.key_path)?;
+        *self
+            .current
+            .write()
+            .map_err(|_| ApiError::InternalError("Certificate lock is poisoned".to_owned()))? = key;
+        Ok(())
+    }
+
+    /// Reload the certificate each time the process receives SIGHUP
+    pub fn reload_on_sighup(self: &Arc<Self>) -> Result<(), ApiError> {
+        let mut hangups = signal(SignalKind::hangup())?;
+        let cert = self.clone();
+        actix_web::rt::spawn(async move {
+            while hangups.recv().await.is_some() {
+                match cert.reload() {
+                    Ok(()) => info!("TLS certificate reloaded from {}", cert.cert_path),
+                    Err(e) => error!("TLS certificate not reloaded : {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _: ClientHello) -> Option<CertifiedKey> {
+        self.current.read().ok().map(|key| key.clone())
+    }
+}
+
+fn read_certified_key(cert_path: &str, key_path: &str) -> Result<CertifiedKey, ApiError> {
+    let invalid = |what: &str, path: &str| {
+        ApiError::InternalError(format!("Invalid TLS {} : {}", what, path))
+    };
+
+    let chain = certs(&mut BufReader::new(File::open(cert_path)?))
+        .map_err(|_| invalid("certificate", cert_path))?;
+    if chain.is_empty() {
+        return Err(invalid("certificate", cert_path));
+    }
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?))
+        .map_err(|_| invalid("key", key_path))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(File::open(key_path)?))
+            .map_err(|_| invalid("key", key_path))?;
+    }
+    let key = keys.first().ok_or_else(|| invalid("key", key_path))?;
+    let key = any_supported_type(key).map_err(|_| invalid("key", key_path))?;
+
+    Ok(CertifiedKey::new(chain, Arc::new(key)))
+}
+
+/// The rustls configuration serving the `tls_cert_path` certificate, when one is set
+pub fn tls_from_settings(settings: &Settings) -> Result<Option<ServerConfig>, ApiError> {
+    let (cert_path, key_path) = match (&settings.tls_cert_path, &settings.tls_key_path) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) => return Ok(None),
+        _ => {
+            return Err(ApiError::InternalError(
+                "tls_cert_path and tls_key_path must be set together".to_owned(),
+            ))
+        }
+    };
+
+    let cert = ReloadableCert::load(cert_path, key_path)?;
+    cert.reload_on_sighup()?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = cert;
+    Ok(Some(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_addresses_are_parsed() {
+        assert_eq!(
+            listeners("127.0.0.1:8080, [::1]:8443,unix:/run/skeleton.sock").unwrap(),
+            vec![
+                Listener::Tcp("127.0.0.1:8080".to_owned()),
+                Listener::Tcp("[::1]:8443".to_owned()),
+                Listener::Unix(PathBuf::from("/run/skeleton.sock")),
+            ]
+        );
+        assert!(listeners("").is_err());
+        assert!(listeners("127.0.0.1").is_err());
+        assert!(listeners("localhost:http").is_err());
+        assert!(listeners("unix:").is_err());
+    }
+}
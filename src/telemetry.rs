@@ -0,0 +1,500 @@
+use flate2::{write::GzEncoder, Compression};
+use native_tls::TlsConnector;
+use percent_encoding::percent_decode_str;
+use serde_json::{json, Map, Value};
+use url::Url;
+
+use log::{error, warn};
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::errors::ApiError;
+use crate::settings::Settings;
+
+/// Spans sent together to the collector
+const BATCH_SIZE: usize = 512;
+/// Longest time a finished span waits before being sent
+const BATCH_DELAY: Duration = Duration::from_secs(5);
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Spans waiting for the exporter thread, the next ones are dropped
+const QUEUE_SIZE: usize = 4 * BATCH_SIZE;
+
+/// Where a span sits in a trace, possibly started by another service
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpanContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+}
+
+/// Parse a W3C `traceparent` header : `00-<trace id>-<parent id>-<flags>`
+pub fn parse_traceparent(header: &str) -> Option<SpanContext> {
+    let parts: Vec<&str> = header.trim().split('-').collect();
+    match parts.as_slice() {
+        [version, trace_id, span_id, flags]
+            if version.len() == 2
+                && *version != "ff"
+                && trace_id.len() == 32
+                && span_id.len() == 16
+                && flags.len() == 2 =>
+        {
+            let context = SpanContext {
+                trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+                span_id: u64::from_str_radix(span_id, 16).ok()?,
+            };
+            match context.trace_id != 0 && context.span_id != 0 {
+                true => Some(context),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// A closed span, as exported
+#[derive(Debug, Clone)]
+pub struct FinishedSpan {
+    pub name: &'static str,
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub parent_span_id: Option<u64>,
+    /// server, client or internal, from the `otel.kind` field
+    pub kind: String,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Map<String, Value>,
+    /// set by an `otel.status_code = "ERROR"` field
+    pub error: bool,
+}
+
+/// Receives the spans once closed, the logger calls it for every span
+pub trait SpanExporter: Send + Sync {
+    fn export(&self, span: FinishedSpan);
+
+    /// Send the spans still buffered, before the process stops
+    fn flush(&self) {}
+}
+
+enum Command {
+    Span(FinishedSpan),
+    Flush(mpsc::Sender<()>),
+}
+
+/// Sends the spans in batches to an OTLP/HTTP collector, JSON encoded and gzipped,
+/// from a thread of its own so requests never wait for the collector.
+/// While the collector is slow or down, spans past `QUEUE_SIZE` are dropped.
+pub struct OtlpExporter {
+    commands: Mutex<mpsc::SyncSender<Command>>,
+    /// spans dropped since the last batch, logged by the thread
+    dropped: Arc<AtomicUsize>,
+}
+
+/// How to reach the collector, besides its url
+#[derive(Debug, Clone, Default)]
+pub struct Collector {
+    /// sent with every export, like the credentials of a hosted collector
+    pub headers: Vec<(String, String)>,
+    /// `http://proxy:3128`, none to connect directly
+    pub proxy: Option<String>,
+}
+
+impl OtlpExporter {
+    /// `endpoint` is the collector base url, `http://localhost:4318` for a local one
+    pub fn new(endpoint: &str, service: &str, collector: Collector) -> Result<Self, ApiError> {
+        let mut url = Url::parse(endpoint)
+            .map_err(|e| ApiError::InternalError(format!("Invalid OTLP endpoint : {}", e)))?;
+        if !url.path().ends_with("/v1/traces") {
+            let path = format!("{}/v1/traces", url.path().trim_end_matches('/'));
+            url.set_path(&path);
+        }
+        if !["http", "https"].contains(&url.scheme()) || url.host_str().is_none() {
+            return Err(ApiError::InternalError(format!(
+                "Invalid OTLP endpoint : {}",
+                endpoint
+            )));
+        }
+
+        let mut agent = ureq::AgentBuilder::new()
+            .timeout_connect(TIMEOUT)
+            .timeout(TIMEOUT)
+            .tls_connector(Arc::new(TlsConnector::new()?));
+        if let Some(proxy) = &collector.proxy {
+            let proxy = ureq::Proxy::new(proxy)
+                .map_err(|e| ApiError::InternalError(format!("Invalid OTLP proxy : {}", e)))?;
+            agent = agent.proxy(proxy);
+        }
+        let agent = agent.build();
+        let headers = collector.headers;
+
+        let (commands, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let service = service.to_owned();
+        let lost = dropped.clone();
+        thread::spawn(move || {
+            let mut batch = Vec::new();
+            let mut deadline = Instant::now() + BATCH_DELAY;
+            loop {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                let flushed = match receiver.recv_timeout(timeout) {
+                    Ok(Command::Span(span)) => {
+                        batch.push(span);
+                        if batch.len() < BATCH_SIZE {
+                            continue;
+                        }
+                        None
+                    }
+                    Ok(Command::Flush(done)) => Some(done),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                };
+                if !batch.is_empty() {
+                    let body = encode(&service, &batch).to_string();
+                    if let Err(e) = post(&agent, &url, &headers, &body) {
+                        warn!("{} spans not exported : {}", batch.len(), e);
+                    }
+                    batch.clear();
+                }
+                match lost.swap(0, Ordering::Relaxed) {
+                    0 => {}
+                    lost => warn!("{} spans dropped, the export queue was full", lost),
+                }
+                if let Some(done) = flushed {
+                    let _ = done.send(());
+                }
+                deadline = Instant::now() + BATCH_DELAY;
+            }
+        });
+
+        Ok(OtlpExporter {
+            commands: Mutex::new(commands),
+            dropped,
+        })
+    }
+}
+
+impl SpanExporter for OtlpExporter {
+    /// Never blocks : the span is dropped when the queue is full
+    fn export(&self, span: FinishedSpan) {
+        if let Ok(commands) = self.commands.lock() {
+            if let Err(TrySendError::Full(_)) = commands.try_send(Command::Span(span)) {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if let Ok(commands) = self.commands.lock() {
+            let _ = commands.send(Command::Flush(done));
+        }
+        if wait.recv_timeout(TIMEOUT).is_err() {
+            error!("Spans not flushed in {:?}", TIMEOUT);
+        }
+    }
+}
+
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn attribute(key: &str, value: &Value) -> Value {
+    let value = match value {
+        Value::Bool(value) => json!({ "boolValue": value }),
+        Value::Number(number) if number.is_f64() => json!({ "doubleValue": number }),
+        Value::Number(number) => json!({ "intValue": number.to_string() }),
+        Value::String(value) => json!({ "stringValue": value }),
+        other => json!({ "stringValue": other.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+/// The OTLP/JSON export request of a batch
+fn encode(service: &str, spans: &[FinishedSpan]) -> Value {
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|span| {
+            let mut encoded = json!({
+                "traceId": format!("{:032x}", span.trace_id),
+                "spanId": format!("{:016x}", span.span_id),
+                "name": span.name,
+                "kind": match span.kind.as_str() {
+                    "server" => 2,
+                    "client" => 3,
+                    _ => 1,
+                },
+                "startTimeUnixNano": nanos(span.start),
+                "endTimeUnixNano": nanos(span.end),
+                "attributes": span
+                    .attributes
+                    .iter()
+                    .map(|(key, value)| attribute(key, value))
+                    .collect::<Vec<_>>(),
+                "status": { "code": if span.error { 2 } else { 0 } },
+            });
+            if let Some(parent) = span.parent_span_id {
+                encoded["parentSpanId"] = format!("{:016x}", parent).into();
+            }
+            encoded
+        })
+        .collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": { "attributes": [attribute("service.name", &service.into())] },
+            "scopeSpans": [{ "scope": { "name": service }, "spans": spans }],
+        }]
+    })
+}
+
+/// Post a batch, any answer but a 2xx being an error
+fn post(
+    agent: &ureq::Agent,
+    url: &Url,
+    headers: &[(String, String)],
+    body: &str,
+) -> Result<(), ApiError> {
+    let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+    gzip.write_all(body.as_bytes())?;
+    let body = gzip.finish()?;
+
+    let mut request = agent
+        .post(url.as_str())
+        .set("Content-Type", "application/json")
+        .set("Content-Encoding", "gzip");
+    for (name, value) in headers {
+        request = request.set(name, value);
+    }
+    match request.send_bytes(&body) {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(status, response)) => Err(ApiError::InternalError(format!(
+            "Collector answered {} {}",
+            status,
+            response.status_text()
+        ))),
+        Err(e) => Err(ApiError::InternalError(e.to_string())),
+    }
+}
+
+/// The headers of `otel_headers` : `key=value` pairs separated by commas, the values
+/// percent-encoded, as in `OTEL_EXPORTER_OTLP_HEADERS`
+pub fn parse_headers(headers: &str) -> Result<Vec<(String, String)>, ApiError> {
+    headers
+        .split(',')
+        .map(str::trim)
+        .filter(|header| !header.is_empty())
+        .map(|header| match header.split_once('=') {
+            Some((name, value)) if !name.trim().is_empty() => Ok((
+                name.trim().to_owned(),
+                percent_decode_str(value.trim())
+                    .decode_utf8_lossy()
+                    .into_owned(),
+            )),
+            _ => Err(ApiError::InternalError(format!(
+                "Invalid OTLP header, key=value expected : {}",
+                header
+            ))),
+        })
+        .collect()
+}
+
+/// The exporter of `otel_endpoint`, traces are not exported when it is not set
+pub fn from_settings(settings: &Settings) -> Result<Option<Arc<OtlpExporter>>, ApiError> {
+    match &settings.otel_endpoint {
+        Some(endpoint) => Ok(Some(Arc::new(OtlpExporter::new(
+            endpoint,
+            &settings.otel_service_name,
+            Collector {
+                headers: parse_headers(settings.otel_headers.as_deref().unwrap_or(""))?,
+                proxy: settings.otel_proxy.clone(),
+            },
+        )?))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::{Format, Logger};
+    use flate2::read::GzDecoder;
+    use std::io::{BufRead, Read};
+    use std::net::TcpListener;
+
+    /// Keeps the spans, for the tests
+    #[derive(Clone, Default)]
+    struct MemoryExporter(Arc<Mutex<Vec<FinishedSpan>>>);
+
+    impl SpanExporter for MemoryExporter {
+        fn export(&self, span: FinishedSpan) {
+            self.0.lock().unwrap().push(span);
+        }
+    }
+
+    #[test]
+    fn traceparent_is_parsed() {
+        assert_eq!(
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            Some(SpanContext {
+                trace_id: 0x4bf92f3577b34da6a3ce929d0e0e4736,
+                span_id: 0x00f067aa0ba902b7,
+            })
+        );
+        assert!(
+            parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none()
+        );
+        assert!(
+            parse_traceparent("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none()
+        );
+        assert!(parse_traceparent("00-4bf92f3577b34da6-00f067aa0ba902b7-01").is_none());
+        assert!(parse_traceparent("garbage").is_none());
+    }
+
+    #[test]
+    fn spans_continue_the_incoming_trace() {
+        let exporter = MemoryExporter::default();
+        let logger = Logger::new(
+            Format::Json,
+            "info".parse().unwrap(),
+            Box::new(std::io::sink()),
+            Some(Arc::new(exporter.clone())),
+        );
+
+        tracing::subscriber::with_default(logger, || {
+            let request = tracing::info_span!(
+                "request",
+                otel.kind = "server",
+                traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+                password = "hunter2",
+            );
+            let _request = request.enter();
+            tracing::info_span!("db.user.auth", db.system = "postgresql").in_scope(|| ());
+        });
+
+        let spans = exporter.0.lock().unwrap();
+        assert_eq!(spans.len(), 2);
+        let (query, request) = (&spans[0], &spans[1]);
+
+        assert_eq!(request.name, "request");
+        assert_eq!(request.kind, "server");
+        assert_eq!(request.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+        assert_eq!(request.parent_span_id, Some(0x00f067aa0ba902b7));
+        assert_eq!(request.attributes["password"], "[redacted]");
+        assert!(!request.attributes.contains_key("traceparent"));
+
+        assert_eq!(query.name, "db.user.auth");
+        assert_eq!(query.trace_id, request.trace_id);
+        assert_eq!(query.parent_span_id, Some(request.span_id));
+        assert_eq!(query.attributes["db.system"], "postgresql");
+        assert!(query.start >= request.start && query.end <= request.end);
+    }
+
+    #[test]
+    fn spans_are_posted_to_the_collector() {
+        let collector = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!(
+            "http://{}/?tenant=skeleton",
+            collector.local_addr().unwrap()
+        );
+        let received = thread::spawn(move || {
+            let (stream, _) = collector.accept().unwrap();
+            let mut reader = std::io::BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut length = 0;
+            let mut headers = Vec::new();
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header == "\r\n" {
+                    break;
+                }
+                let header = header.trim_end().to_lowercase();
+                if let Some(value) = header.strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                headers.push(header);
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            let mut json = String::new();
+            GzDecoder::new(&body[..]).read_to_string(&mut json).unwrap();
+            (request_line, headers, json)
+        });
+
+        let collector = Collector {
+            headers: parse_headers("authorization=Bearer%20t0ken").unwrap(),
+            proxy: None,
+        };
+        let exporter = OtlpExporter::new(&endpoint, "skeleton-test", collector).unwrap();
+        exporter.export(FinishedSpan {
+            name: "request",
+            trace_id: 1,
+            span_id: 2,
+            parent_span_id: None,
+            kind: "server".to_owned(),
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            attributes: Map::new(),
+            error: true,
+        });
+        exporter.flush();
+
+        let (request_line, headers, body) = received.join().unwrap();
+        assert_eq!(request_line, "POST /v1/traces?tenant=skeleton HTTP/1.1\r\n");
+        assert!(headers.contains(&"authorization: bearer t0ken".to_owned()));
+        assert!(headers.contains(&"content-encoding: gzip".to_owned()));
+        let body: Value = serde_json::from_str(&body).unwrap();
+        let span = &body["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], "00000000000000000000000000000001");
+        assert_eq!(span["spanId"], "0000000000000002");
+        assert_eq!(span["kind"], 2);
+        assert_eq!(span["status"]["code"], 2);
+    }
+
+    #[test]
+    fn spans_are_dropped_when_the_queue_is_full() {
+        // a collector accepting connections but never answering keeps the thread busy
+        let collector = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", collector.local_addr().unwrap());
+        let exporter = OtlpExporter::new(&endpoint, "skeleton-test", Collector::default()).unwrap();
+
+        for span_id in 0..(BATCH_SIZE + QUEUE_SIZE + 10) as u64 {
+            exporter.export(FinishedSpan {
+                name: "request",
+                trace_id: 1,
+                span_id,
+                parent_span_id: None,
+                kind: "server".to_owned(),
+                start: SystemTime::now(),
+                end: SystemTime::now(),
+                attributes: Map::new(),
+                error: false,
+            });
+        }
+        assert!(exporter.dropped.load(Ordering::Relaxed) >= 10);
+    }
+
+    #[test]
+    fn collector_headers_are_parsed() {
+        assert_eq!(
+            parse_headers(" api-key=s3cr%3Dt , x-tenant=skeleton,").unwrap(),
+            vec![
+                ("api-key".to_owned(), "s3cr=t".to_owned()),
+                ("x-tenant".to_owned(), "skeleton".to_owned()),
+            ]
+        );
+        assert!(parse_headers("").unwrap().is_empty());
+        assert!(parse_headers("api-key").is_err());
+        assert!(parse_headers("=value").is_err());
+    }
+}
@@ -1,22 +1,252 @@
-use ramhorns::{Content, Template};
+use ramhorns::Content;
 
+use super::Templates;
+use crate::db::models::User;
 use crate::errors::ApiError;
-use std::env;
+use crate::i18n::Locales;
+
+#[derive(Content)]
+struct Notice<'a> {
+    message: &'a str,
+}
 
 #[derive(Content)]
 struct DashboardLogin<'a> {
+    lang: &'a str,
+    title: &'a str,
+    csrf_token: &'a str,
+    /// page to go back to once logged in
+    next: &'a str,
+    error: Option<Notice<'a>>,
+    email_label: &'a str,
+    password_label: &'a str,
+    submit_label: &'a str,
+}
+
+#[derive(Content)]
+struct UserRow {
+    id: i32,
+    username: String,
+    email: String,
+    role: String,
+    status: String,
+}
+
+#[derive(Content)]
+struct DashboardUsers<'a> {
+    lang: &'a str,
+    title: &'a str,
+    users_label: &'a str,
+    search: &'a str,
+    search_label: &'a str,
+    username_label: &'a str,
+    email_label: &'a str,
+    role_label: &'a str,
+    status_label: &'a str,
+    users: Vec<UserRow>,
+}
+
+/// A button posting a form to `url`
+#[derive(Content)]
+struct Action<'a> {
+    url: String,
+    label: String,
+    csrf_token: &'a str,
+}
+
+#[derive(Content)]
+struct DashboardUser<'a> {
+    lang: &'a str,
     title: &'a str,
+    back_label: &'a str,
+    edit_label: &'a str,
+    id: i32,
+    username: &'a str,
+    username_label: &'a str,
+    email: &'a str,
+    email_label: &'a str,
+    locale: &'a str,
+    locale_label: &'a str,
+    role: &'a str,
+    role_label: &'a str,
+    status: &'a str,
+    status_label: &'a str,
+    created_at: String,
+    created_label: &'a str,
+    actions: Vec<Action<'a>>,
 }
 
-pub fn dashboard_login() -> Result<String, ApiError> {
-    let tpl = Template::from_file(format!(
-        "{}/dashboard_login.html",
-        env::var("TEMPLATES_PATH")?
-    ))?;
+#[derive(Content)]
+struct LocaleOption<'a> {
+    code: &'a str,
+    selected: &'a str,
+}
 
+#[derive(Content)]
+struct DashboardUserEdit<'a> {
+    lang: &'a str,
+    title: &'a str,
+    csrf_token: &'a str,
+    back_label: &'a str,
+    id: i32,
+    username: &'a str,
+    username_label: &'a str,
+    locale_label: &'a str,
+    locales: Vec<LocaleOption<'a>>,
+    save_label: &'a str,
+}
+
+fn title(templates: &Templates, locales: &Locales, locale: &str) -> String {
+    locales.t(
+        locale,
+        "dashboard-title",
+        &[("platform", templates.platform())],
+    )
+}
+
+fn role(locales: &Locales, locale: &str, user: &User) -> String {
+    match user.is_admin {
+        true => locales.t(locale, "dashboard-admin", &[]),
+        _ => locales.t(locale, "dashboard-user", &[]),
+    }
+}
+
+fn status(locales: &Locales, locale: &str, user: &User) -> String {
+    match user.is_active {
+        true => locales.t(locale, "dashboard-active", &[]),
+        _ => locales.t(locale, "dashboard-inactive", &[]),
+    }
+}
+
+pub fn dashboard_login(
+    templates: &Templates,
+    locales: &Locales,
+    locale: &str,
+    csrf_token: &str,
+    next: &str,
+    error: Option<&str>,
+) -> Result<String, ApiError> {
     let content = DashboardLogin {
-        title: &format!("{} Dashboard", env::var("PLATFORM_NAME")?),
+        lang: locales.resolve(locale),
+        title: &title(templates, locales, locale),
+        csrf_token,
+        next,
+        error: error.map(|message| Notice { message }),
+        email_label: &locales.t(locale, "dashboard-email", &[]),
+        password_label: &locales.t(locale, "dashboard-password", &[]),
+        submit_label: &locales.t(locale, "dashboard-submit", &[]),
+    };
+
+    templates.render("dashboard_login.html", &content)
+}
+
+pub fn dashboard_users(
+    templates: &Templates,
+    locales: &Locales,
+    locale: &str,
+    search: &str,
+    users: &[User],
+) -> Result<String, ApiError> {
+    let content = DashboardUsers {
+        lang: locales.resolve(locale),
+        title: &title(templates, locales, locale),
+        users_label: &locales.t(locale, "dashboard-users", &[]),
+        search,
+        search_label: &locales.t(locale, "dashboard-search", &[]),
+        username_label: &locales.t(locale, "dashboard-username", &[]),
+        email_label: &locales.t(locale, "dashboard-email", &[]),
+        role_label: &locales.t(locale, "dashboard-role", &[]),
+        status_label: &locales.t(locale, "dashboard-status", &[]),
+        users: users
+            .iter()
+            .map(|user| UserRow {
+                id: user.id,
+                username: user.username.to_owned(),
+                email: user.email.to_owned(),
+                role: role(locales, locale, user),
+                status: status(locales, locale, user),
+            })
+            .collect(),
+    };
+
+    templates.render("dashboard_users.html", &content)
+}
+
+pub fn dashboard_user(
+    templates: &Templates,
+    locales: &Locales,
+    locale: &str,
+    csrf_token: &str,
+    user: &User,
+) -> Result<String, ApiError> {
+    let action = |name: &str, key: &str| Action {
+        url: format!("/dashboard/users/{}/{}", user.id, name),
+        label: locales.t(locale, key, &[]),
+        csrf_token,
+    };
+    let mut actions = Vec::new();
+    match user.is_admin {
+        true => actions.push(action("demote", "dashboard-demote")),
+        _ => {
+            actions.push(action("promote", "dashboard-promote"));
+            actions.push(action("impersonate", "dashboard-impersonate"));
+        }
+    }
+    match user.is_active {
+        true => actions.push(action("deactivate", "dashboard-deactivate")),
+        _ => actions.push(action("activate", "dashboard-activate")),
+    }
+
+    let content = DashboardUser {
+        lang: locales.resolve(locale),
+        title: &title(templates, locales, locale),
+        back_label: &locales.t(locale, "dashboard-back", &[]),
+        edit_label: &locales.t(locale, "dashboard-edit", &[]),
+        id: user.id,
+        username: &user.username,
+        username_label: &locales.t(locale, "dashboard-username", &[]),
+        email: &user.email,
+        email_label: &locales.t(locale, "dashboard-email", &[]),
+        locale: &user.locale,
+        locale_label: &locales.t(locale, "dashboard-locale", &[]),
+        role: &role(locales, locale, user),
+        role_label: &locales.t(locale, "dashboard-role", &[]),
+        status: &status(locales, locale, user),
+        status_label: &locales.t(locale, "dashboard-status", &[]),
+        created_at: user.created_at.format("%Y-%m-%d %H:%M").to_string(),
+        created_label: &locales.t(locale, "dashboard-created", &[]),
+        actions,
+    };
+
+    templates.render("dashboard_user.html", &content)
+}
+
+pub fn dashboard_user_edit(
+    templates: &Templates,
+    locales: &Locales,
+    locale: &str,
+    csrf_token: &str,
+    user: &User,
+) -> Result<String, ApiError> {
+    let content = DashboardUserEdit {
+        lang: locales.resolve(locale),
+        title: &title(templates, locales, locale),
+        csrf_token,
+        back_label: &locales.t(locale, "dashboard-back", &[]),
+        id: user.id,
+        username: &user.username,
+        username_label: &locales.t(locale, "dashboard-username", &[]),
+        locale_label: &locales.t(locale, "dashboard-locale", &[]),
+        locales: locales
+            .available()
+            .into_iter()
+            .map(|code| LocaleOption {
+                code,
+                selected: if code == user.locale { "selected" } else { "" },
+            })
+            .collect(),
+        save_label: &locales.t(locale, "dashboard-save", &[]),
     };
 
-    Ok(tpl.render(&content))
+    templates.render("dashboard_user_edit.html", &content)
 }
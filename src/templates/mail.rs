@@ -1,15 +1,17 @@
-use ramhorns::{Content, Template};
-
-use std::env;
+use ramhorns::Content;
 
+use super::Templates;
 use crate::errors::ApiError;
+use crate::i18n::Locales;
 
 #[derive(Content)]
 struct EmailContent<'a> {
+    lang: &'a str,
     supheader: &'a str,
     header: &'a str,
     paragraphs: Vec<EmailParagraphs<'a>>,
     buttons: Vec<EmailButtons<'a>>,
+    footer: &'a str,
 }
 
 #[derive(Content)]
@@ -23,74 +25,98 @@ struct EmailButtons<'a> {
     url: &'a str,
 }
 
-pub fn register_user(username: &str) -> Result<String, ApiError> {
-    let tpl = Template::from_file(format!("{}/mail.html", env::var("TEMPLATES_PATH")?))?;
-
-    let text = format!("You are now register on {} !", env::var("PLATFORM_NAME")?);
-    let mut paragraphs = Vec::new();
-    paragraphs.push(EmailParagraphs { paragraph: &text });
-
+fn render(
+    templates: &Templates,
+    locales: &Locales,
+    locale: &str,
+    header: &str,
+    paragraphs: &[String],
+    buttons: Vec<EmailButtons>,
+) -> Result<String, ApiError> {
+    let platform = templates.platform();
     let content = EmailContent {
+        lang: locales.resolve(locale),
         supheader: "",
-        header: &format!("Welcome, {}", username),
-        paragraphs: paragraphs,
-        buttons: Vec::new(),
+        header,
+        paragraphs: paragraphs
+            .iter()
+            .map(|paragraph| EmailParagraphs { paragraph })
+            .collect(),
+        buttons,
+        footer: &locales.t(locale, "mail-footer", &[("platform", platform)]),
     };
 
-    Ok(tpl.render(&content))
+    templates.render("mail.html", &content)
 }
 
-pub fn reset_token(mail: &str, username: &str, token: &str) -> Result<String, ApiError> {
-    let tpl = Template::from_file(format!("{}/mail.html", env::var("TEMPLATES_PATH")?))?;
-
-    let url = format!("domain.your/reset_password/{}/{}", mail, token);
-    let mut buttons = Vec::new();
-    buttons.push(EmailButtons {
-        text: "Reset your password",
-        url: &url,
-    });
-
-    let text = format!(
-        "We have received a request to reset the password for the {} account associated with {}.",
-        username, mail
-    );
-    let mut paragraphs = Vec::new();
-    paragraphs.push(EmailParagraphs { paragraph: &text });
-
-    paragraphs.push( EmailParagraphs {
-        paragraph : "If you did not request new password, please let us know immediately by replying to this email.",
-    });
-
-    paragraphs.push(EmailParagraphs {
-        paragraph: "You can reset your password by clicking the link below :",
-    });
-
-    let content = EmailContent {
-        supheader: "",
-        header: &format!("{} : Password Reset", env::var("PLATFORM_NAME")?),
-        paragraphs: paragraphs,
-        buttons: buttons,
-    };
-
-    Ok(tpl.render(&content))
+pub fn register_user(
+    templates: &Templates,
+    locales: &Locales,
+    locale: &str,
+    username: &str,
+) -> Result<String, ApiError> {
+    let platform = templates.platform();
+    render(
+        templates,
+        locales,
+        locale,
+        &locales.t(locale, "register-header", &[("username", username)]),
+        &[locales.t(locale, "register-text", &[("platform", platform)])],
+        Vec::new(),
+    )
 }
 
-pub fn change_password_success(mail: &str, username: &str) -> Result<String, ApiError> {
-    let tpl = Template::from_file(format!("{}/mail.html", env::var("TEMPLATES_PATH")?))?;
-
-    let text = format!(
-        "The password for the {} account associated with {} has changed.",
-        username, mail
-    );
-    let mut paragraphs = Vec::new();
-    paragraphs.push(EmailParagraphs { paragraph: &text });
-
-    let content = EmailContent {
-        supheader: "",
-        header: &format!("{} : Password Reset Success", env::var("PLATFORM_NAME")?),
-        paragraphs: paragraphs,
-        buttons: Vec::new(),
-    };
+pub fn reset_token(
+    templates: &Templates,
+    locales: &Locales,
+    locale: &str,
+    mail: &str,
+    username: &str,
+    url: &str,
+) -> Result<String, ApiError> {
+    let platform = templates.platform();
+    let button = locales.t(locale, "reset-button", &[]);
+
+    render(
+        templates,
+        locales,
+        locale,
+        &locales.t(locale, "reset-subject", &[("platform", platform)]),
+        &[
+            locales.t(
+                locale,
+                "reset-text",
+                &[("username", username), ("email", mail)],
+            ),
+            locales.t(locale, "reset-warning", &[]),
+            locales.t(locale, "reset-instructions", &[]),
+        ],
+        vec![EmailButtons { text: &button, url }],
+    )
+}
 
-    Ok(tpl.render(&content))
+pub fn change_password_success(
+    templates: &Templates,
+    locales: &Locales,
+    locale: &str,
+    mail: &str,
+    username: &str,
+) -> Result<String, ApiError> {
+    let platform = templates.platform();
+    render(
+        templates,
+        locales,
+        locale,
+        &locales.t(
+            locale,
+            "password-changed-subject",
+            &[("platform", platform)],
+        ),
+        &[locales.t(
+            locale,
+            "password-changed-text",
+            &[("username", username), ("email", mail)],
+        )],
+        Vec::new(),
+    )
 }
@@ -1,2 +1,295 @@
 pub mod dashboard;
 pub mod mail;
+
+use notify_debouncer_mini::notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult, Debouncer};
+use ramhorns::{Content, Template};
+
+use log::{error, info};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::errors::ApiError;
+use crate::settings::Settings;
+
+/// Templates and assets the api can't run without, checked when the registry is loaded
+const REQUIRED: &[&str] = &[
+    "mail.html",
+    "dashboard_login.html",
+    "dashboard_users.html",
+    "dashboard_user.html",
+    "dashboard_user_edit.html",
+    "images/logo.png",
+];
+
+/// Compiled templates, loaded once at startup and shared through app data.
+#[derive(Clone)]
+pub struct Templates {
+    path: PathBuf,
+    platform: String,
+    compiled: Arc<RwLock<HashMap<String, Template<'static>>>>,
+    /// files of the directory read once, like the logo of the mails
+    assets: Arc<RwLock<HashMap<String, Arc<Vec<u8>>>>>,
+}
+
+impl Templates {
+    /// Parse every `.html` file of the directory, failing on the first invalid one.
+    /// `platform` is the name displayed by the pages and mails.
+    pub fn load<P: Into<PathBuf>>(path: P, platform: &str) -> Result<Self, ApiError> {
+        let path = path.into();
+        let compiled = compile_dir(&path)?;
+        info!("{} templates loaded from {:?}", compiled.len(), path);
+
+        Ok(Templates {
+            path,
+            platform: platform.to_owned(),
+            compiled: Arc::new(RwLock::new(compiled)),
+            assets: Arc::new(RwLock::new(HashMap::new())),
+        })
+    }
+
+    pub fn platform(&self) -> &str {
+        &self.platform
+    }
+
+    pub fn render<C: Content>(&self, name: &str, content: &C) -> Result<String, ApiError> {
+        let compiled = self
+            .compiled
+            .read()
+            .map_err(|_| ApiError::InternalError("Templates lock is poisoned".to_owned()))?;
+        let tpl = compiled
+            .get(name)
+            .ok_or_else(|| ApiError::InternalError(format!("Unknown template : {}", name)))?;
+        Ok(tpl.render(content))
+    }
+
+    /// A file of the directory, read on first use then kept until a reload
+    pub fn asset(&self, name: &str) -> Result<Arc<Vec<u8>>, ApiError> {
+        let poisoned = || ApiError::InternalError("Template assets lock is poisoned".to_owned());
+        if let Some(asset) = self.assets.read().map_err(|_| poisoned())?.get(name) {
+            return Ok(asset.clone());
+        }
+        let asset = Arc::new(fs::read(self.path.join(name))?);
+        self.assets
+            .write()
+            .map_err(|_| poisoned())?
+            .insert(name.to_owned(), asset.clone());
+        Ok(asset)
+    }
+
+    /// Whether every required template is compiled and the directory still readable
+    pub fn check(&self) -> Result<(), ApiError> {
+        check_required(
+            &self.path,
+            &*self
+                .compiled
+                .read()
+                .map_err(|_| ApiError::InternalError("Templates lock is poisoned".to_owned()))?,
+        )?;
+        fs::read_dir(&self.path)?;
+        Ok(())
+    }
+
+    /// Recompile the whole directory, keeping the current templates if any of them is invalid
+    pub fn reload(&self) -> Result<(), ApiError> {
+        let compiled = compile_dir(&self.path)?;
+        *self
+            .compiled
+            .write()
+            .map_err(|_| ApiError::InternalError("Templates lock is poisoned".to_owned()))? =
+            compiled;
+        self.assets
+            .write()
+            .map_err(|_| ApiError::InternalError("Template assets lock is poisoned".to_owned()))?
+            .clear();
+        Ok(())
+    }
+
+    /// Reload the templates and assets when a file of the directory or of its subdirectories
+    /// changes, the events of a same `delay` being reloaded once. The directory is watched
+    /// until the returned watcher is dropped.
+    /// Meant for development, where templates are edited while the api runs.
+    pub fn watch(&self, delay: Duration) -> Result<Watcher, ApiError> {
+        let templates = self.clone();
+        let mut debouncer = new_debouncer(delay, move |events: DebounceEventResult| {
+            if let Err(e) = events {
+                return error!("Templates watch failed : {}", e);
+            }
+            match templates.reload() {
+                Ok(()) => info!("Templates reloaded"),
+                Err(e) => error!("Templates not reloaded : {}", e),
+            }
+        })
+        .map_err(|e| ApiError::InternalError(format!("Templates not watched : {}", e)))?;
+        debouncer
+            .watcher()
+            .watch(&self.path, RecursiveMode::Recursive)
+            .map_err(|e| ApiError::InternalError(format!("Templates not watched : {}", e)))?;
+        Ok(Watcher {
+            _debouncer: debouncer,
+        })
+    }
+}
+
+/// Watch of a templates directory, stopped when dropped
+pub struct Watcher {
+    _debouncer: Debouncer<RecommendedWatcher>,
+}
+
+fn compile_dir(path: &Path) -> Result<HashMap<String, Template<'static>>, ApiError> {
+    let mut compiled = HashMap::new();
+    for entry in fs::read_dir(path)? {
+        let file = entry?.path();
+        if file.extension().and_then(|e| e.to_str()) != Some("html") {
+            continue;
+        }
+        let name = file
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default()
+            .to_owned();
+        let tpl = Template::from_file(&file)
+            .map_err(|e| ApiError::InternalError(format!("Template {} : {}", name, e)))?;
+        compiled.insert(name, tpl);
+    }
+
+    check_required(path, &compiled)?;
+    Ok(compiled)
+}
+
+/// The required `.html` files must be compiled, the other ones present in `path`
+fn check_required(
+    path: &Path,
+    compiled: &HashMap<String, Template<'static>>,
+) -> Result<(), ApiError> {
+    for name in REQUIRED {
+        let found = match name.ends_with(".html") {
+            true => compiled.contains_key(*name),
+            false => path.join(name).is_file(),
+        };
+        if !found {
+            return Err(ApiError::InternalError(format!(
+                "Missing template : {}",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Load the templates of `templates_path`
+pub fn from_settings(settings: &Settings) -> Result<Templates, ApiError> {
+    Templates::load(&settings.templates_path, &settings.platform_name)
+}
+
+/// Watch the templates when `templates_hot_reload` is set, for as long as the watcher is kept
+pub fn watcher_from_settings(
+    settings: &Settings,
+    templates: &Templates,
+) -> Result<Option<Watcher>, ApiError> {
+    match settings.templates_hot_reload {
+        true => templates.watch(Duration::from_millis(200)).map(Some),
+        false => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn copy_templates() -> PathBuf {
+        let path = env::temp_dir().join(format!("skeleton-templates-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(path.join("images")).unwrap();
+        for name in REQUIRED {
+            fs::copy(
+                Path::new(env!("CARGO_MANIFEST_DIR"))
+                    .join("templates")
+                    .join(name),
+                path.join(name),
+            )
+            .unwrap();
+        }
+        path
+    }
+
+    #[derive(Content)]
+    struct Title<'a> {
+        title: &'a str,
+    }
+
+    #[test]
+    fn templates_are_compiled_once_and_reloaded() {
+        let path = copy_templates();
+        let templates = Templates::load(&path, "Skeleton").unwrap();
+        let html = templates
+            .render("dashboard_login.html", &Title { title: "Skeleton" })
+            .unwrap();
+        assert!(html.contains("<h1>Skeleton</h1>"));
+        assert!(templates
+            .render("unknown.html", &Title { title: "" })
+            .is_err());
+
+        fs::write(path.join("dashboard_login.html"), "<p>{{title}}</p>").unwrap();
+        templates.reload().unwrap();
+        assert_eq!(
+            templates
+                .render("dashboard_login.html", &Title { title: "New" })
+                .unwrap(),
+            "<p>New</p>"
+        );
+
+        // an invalid template keeps the previous ones
+        fs::write(path.join("dashboard_login.html"), "{{#title}}").unwrap();
+        assert!(templates.reload().is_err());
+        assert!(Templates::load(&path, "Skeleton").is_err());
+        assert_eq!(
+            templates
+                .render("dashboard_login.html", &Title { title: "Old" })
+                .unwrap(),
+            "<p>Old</p>"
+        );
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn subdirectories_are_watched_until_the_watcher_is_dropped() {
+        let path = copy_templates();
+        let templates = Templates::load(&path, "Skeleton").unwrap();
+        let logo = templates.asset("images/logo.png").unwrap();
+        let watcher = templates.watch(Duration::from_millis(50)).unwrap();
+
+        let reloaded = |expected: &[u8], tries| {
+            (0..tries).any(|_| {
+                std::thread::sleep(Duration::from_millis(50));
+                templates.asset("images/logo.png").unwrap().as_slice() == expected
+            })
+        };
+        fs::write(path.join("images/logo.png"), b"new logo").unwrap();
+        assert!(reloaded(b"new logo", 100));
+
+        drop(watcher);
+        fs::write(path.join("images/logo.png"), logo.as_slice()).unwrap();
+        // well past the 50ms delay
+        assert!(!reloaded(logo.as_slice(), 10));
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn the_logo_is_required() {
+        let path = copy_templates();
+        let templates = Templates::load(&path, "Skeleton").unwrap();
+        assert!(!templates.asset("images/logo.png").unwrap().is_empty());
+
+        fs::remove_file(path.join("images/logo.png")).unwrap();
+        assert!(templates.check().is_err());
+        assert!(Templates::load(&path, "Skeleton").is_err());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+}
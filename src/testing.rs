@@ -0,0 +1,189 @@
+//! Harness of the integration tests : the real [`app`](crate::app::app) on a test
+//! database, with the mails kept in memory and the cookies sent back like a browser.
+//!
+//! Each [`TestApp`] has a pool of a single connection, migrated then kept in a
+//! transaction which is never committed. SQLite builds use a database in memory;
+//! Postgres builds use `TEST_DATABASE_URL`: the tests using it are ignored there,
+//! run them with `cargo test -- --ignored`.
+
+use actix::{Actor, Addr};
+use actix_http::Request;
+use actix_web::body::Body;
+use actix_web::cookie::Cookie;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::{http::StatusCode, test, Error};
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Error as R2D2Error, Pool};
+use diesel::Connection;
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use time::Duration;
+
+use crate::app::{app, AppState};
+use crate::db::repository::Diesel;
+use crate::db::{cache, migrations, DbConnection, DbPool, ReadPool};
+use crate::mails::transport::{CapturedEmail, MemoryTransport, Outbox};
+use crate::mails::{self, Postman, Sender};
+use crate::middlewares::allowlist::IpAllowlist;
+use crate::middlewares::{csrf, session};
+use crate::{i18n, settings, templates, urls};
+
+/// Migrates the connection of a test pool, then opens the transaction it never leaves
+#[derive(Debug)]
+struct TestTransaction;
+
+impl CustomizeConnection<DbConnection, R2D2Error> for TestTransaction {
+    fn on_acquire(&self, conn: &mut DbConnection) -> Result<(), R2D2Error> {
+        migrations::run_pending(conn).expect("Failed to migrate the test database");
+        conn.begin_test_transaction().map_err(R2D2Error::QueryError)
+    }
+}
+
+fn pool() -> DbPool {
+    #[cfg(feature = "sqlite")]
+    let url = ":memory:".to_owned();
+    #[cfg(feature = "postgres")]
+    let url = std::env::var("TEST_DATABASE_URL")
+        .expect("TEST_DATABASE_URL must point to a Postgres database to run these tests");
+
+    Pool::builder()
+        .max_size(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connection_customizer(Box::new(TestTransaction))
+        .build(ConnectionManager::new(url))
+        .expect("Failed to open the test database")
+}
+
+/// The app under test, and what it did
+pub struct TestApp<S> {
+    service: S,
+    pub state: AppState,
+    outbox: Outbox,
+    /// cookies set by the responses, by name
+    jar: BTreeMap<String, Cookie<'static>>,
+}
+
+/// The app of [`crate::settings::MINIMAL`] on a fresh test database
+pub async fn start(
+) -> TestApp<impl Service<Request = Request, Response = ServiceResponse<Body>, Error = Error>> {
+    let pool = pool();
+    let settings = settings::parse(settings::MINIMAL).expect("Invalid test settings");
+    let transport = MemoryTransport::new();
+    let outbox = transport.outbox();
+    let postman: Addr<Postman> = Postman::new(
+        Sender::from_settings(&settings).expect("Invalid test mail sender"),
+        Box::new(transport),
+        None,
+    )
+    .start();
+
+    let session_keys = cache::from_settings(&settings);
+    let state = AppState {
+        repository: Arc::new(Diesel::new(
+            pool.clone(),
+            ReadPool(pool.clone()),
+            session_keys.clone(),
+        )),
+        session_keys,
+        read_pool: ReadPool(pool.clone()),
+        pool,
+        postman,
+        templates: templates::from_settings(&settings).expect("Failed to load templates"),
+        locales: i18n::from_settings(&settings).expect("Failed to load locales"),
+        urls: urls::from_settings(&settings),
+        session_cookie: session::from_settings(&settings).expect("Invalid session cookie"),
+        health_allowlist: IpAllowlist::parse("").unwrap(),
+        metrics_allowlist: IpAllowlist::parse("").unwrap(),
+        settings,
+    };
+    let service = test::init_service(app(&state)).await;
+    TestApp {
+        service,
+        state,
+        outbox,
+        jar: BTreeMap::new(),
+    }
+}
+
+impl<S> TestApp<S>
+where
+    S: Service<Request = Request, Response = ServiceResponse<Body>, Error = Error>,
+{
+    /// Send `req` with the cookies of the jar and its CSRF token, keeping the cookies it sets
+    pub async fn call(&mut self, mut req: test::TestRequest) -> ServiceResponse<Body> {
+        for cookie in self.jar.values() {
+            req = req.cookie(cookie.clone());
+        }
+        if let Some(token) = self.jar.get(csrf::COOKIE) {
+            req = req.header(csrf::HEADER, token.value());
+        }
+        let res = test::call_service(&mut self.service, req.to_request()).await;
+        for cookie in res.response().cookies() {
+            match cookie.max_age() {
+                Some(age) if age <= Duration::zero() => self.jar.remove(cookie.name()),
+                _ => self
+                    .jar
+                    .insert(cookie.name().to_owned(), cookie.into_owned()),
+            };
+        }
+        res
+    }
+
+    /// `call`, with the body read as JSON
+    pub async fn json<T: DeserializeOwned>(&mut self, req: test::TestRequest) -> (StatusCode, T) {
+        let res = self.call(req).await;
+        let status = res.status();
+        (status, test::read_body_json(res).await)
+    }
+
+    /// Send `body` as JSON to `POST uri`
+    pub async fn post(&mut self, uri: &str, body: serde_json::Value) -> StatusCode {
+        let req = test::TestRequest::post().uri(uri).set_json(&body);
+        self.call(req).await.status()
+    }
+
+    pub async fn register(&mut self, username: &str, email: &str, password: &str) -> StatusCode {
+        let body =
+            serde_json::json!({ "username": username, "email": email, "password": password });
+        self.post("/api/v1/user/register", body).await
+    }
+
+    /// Log in, the session cookie going to the jar
+    pub async fn login(&mut self, email: &str, password: &str) -> StatusCode {
+        let body = serde_json::json!({ "email": email, "password": password });
+        self.post("/api/v1/login", body).await
+    }
+
+    /// Whether the jar holds a session cookie
+    pub fn logged_in(&self) -> bool {
+        self.jar.contains_key(self.state.session_cookie.name())
+    }
+
+    /// The session cookie of the jar
+    pub fn session(&self) -> Option<Cookie<'static>> {
+        self.jar.get(self.state.session_cookie.name()).cloned()
+    }
+
+    /// Put back a session cookie in the jar, like a second browser would send it
+    pub fn set_session(&mut self, cookie: Cookie<'static>) {
+        self.jar.insert(cookie.name().to_owned(), cookie);
+    }
+
+    /// Every mail sent so far, once the queued ones are handled
+    pub async fn mails(&self) -> Vec<CapturedEmail> {
+        mails::flush(&self.state.postman)
+            .await
+            .expect("The postman stopped");
+        self.outbox.lock().unwrap().clone()
+    }
+}
+
+/// The first link of a mail starting with `public_url`
+pub fn link(mail: &CapturedEmail, public_url: &str) -> Option<url::Url> {
+    let start = mail.message.find(public_url)?;
+    let end = mail.message[start..]
+        .find(|c: char| c.is_whitespace() || c == '"' || c == '<' || c == ')')
+        .map_or(mail.message.len(), |end| start + end);
+    url::Url::parse(&mail.message[start..end]).ok()
+}
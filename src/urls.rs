@@ -0,0 +1,234 @@
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use ring::hmac;
+
+use chrono::Utc;
+use std::collections::HashMap;
+
+use crate::errors::ApiError;
+use crate::settings::Settings;
+
+/// Characters kept as is in a path segment, the unreserved ones of RFC 3986
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// The flows a mail can link to, each with its own frontend page
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Flow {
+    ResetPassword,
+}
+
+impl Flow {
+    const ALL: [Flow; 1] = [Flow::ResetPassword];
+
+    fn name(self) -> &'static str {
+        match self {
+            Flow::ResetPassword => "reset_password",
+        }
+    }
+
+    /// Setting overriding the route, e.g. `reset_password_route`
+    fn setting(self, settings: &Settings) -> Option<&String> {
+        match self {
+            Flow::ResetPassword => settings.reset_password_route.as_ref(),
+        }
+    }
+
+    fn default_route(self) -> &'static str {
+        match self {
+            Flow::ResetPassword => "/reset_password",
+        }
+    }
+}
+
+/// Builds the public urls put in mails.
+///
+/// Routes are relative to the public base url and may hold `{name}` placeholders,
+/// replaced by percent-encoded values.
+/// Query parameters are signed with an `expires` timestamp and an HMAC `signature`,
+/// so a link can't be forged or altered and nothing sensitive has to be in the path.
+#[derive(Clone)]
+pub struct UrlBuilder {
+    base: String,
+    routes: HashMap<Flow, String>,
+    key: hmac::Key,
+    ttl: i64,
+}
+
+impl UrlBuilder {
+    pub fn new(base: &str, routes: HashMap<Flow, String>, secret: &[u8], ttl: i64) -> Self {
+        UrlBuilder {
+            base: base.trim_end_matches('/').to_owned(),
+            routes,
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            ttl,
+        }
+    }
+
+    pub fn build(
+        &self,
+        flow: Flow,
+        placeholders: &[(&str, &str)],
+        params: &[(&str, &str)],
+    ) -> Result<String, ApiError> {
+        let route = placeholders.iter().fold(
+            self.routes
+                .get(&flow)
+                .map(|route| route.as_str())
+                .unwrap_or_else(|| flow.default_route())
+                .to_owned(),
+            |route, (name, value)| {
+                route.replace(
+                    &format!("{{{}}}", name),
+                    &utf8_percent_encode(value, PATH_SEGMENT).to_string(),
+                )
+            },
+        );
+
+        let expires = (Utc::now().timestamp() + self.ttl).to_string();
+        let signature = self.sign(flow, params, &expires)?;
+
+        let mut query = params.to_vec();
+        query.push(("expires", &expires));
+        query.push(("signature", &signature));
+
+        Ok(format!(
+            "{}{}?{}",
+            self.base,
+            route,
+            serde_urlencoded::to_string(&query)?
+        ))
+    }
+
+    /// Check the parameters received back from a link, in the order they were built with
+    pub fn verify(
+        &self,
+        flow: Flow,
+        params: &[(&str, &str)],
+        expires: i64,
+        signature: &str,
+    ) -> Result<(), ApiError> {
+        let invalid = || ApiError::InternalError("Invalid link".to_owned());
+
+        if expires < Utc::now().timestamp() {
+            return Err(ApiError::InternalError("Expired link".to_owned()));
+        }
+        let signature =
+            base64::decode_config(signature, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+        hmac::verify(
+            &self.key,
+            signed_message(flow, params, &expires.to_string())?.as_bytes(),
+            &signature,
+        )
+        .map_err(|_| invalid())
+    }
+
+    fn sign(&self, flow: Flow, params: &[(&str, &str)], expires: &str) -> Result<String, ApiError> {
+        let tag = hmac::sign(&self.key, signed_message(flow, params, expires)?.as_bytes());
+        Ok(base64::encode_config(tag.as_ref(), base64::URL_SAFE_NO_PAD))
+    }
+}
+
+fn signed_message(flow: Flow, params: &[(&str, &str)], expires: &str) -> Result<String, ApiError> {
+    Ok(format!(
+        "{}|{}|{}",
+        flow.name(),
+        serde_urlencoded::to_string(params)?,
+        expires
+    ))
+}
+
+/// Build the urls from `public_url`, `url_signing_key`, `link_ttl`
+/// and the optional `<flow>_route` overrides
+pub fn from_settings(settings: &Settings) -> UrlBuilder {
+    let routes = Flow::ALL
+        .iter()
+        .filter_map(|flow| {
+            flow.setting(settings)
+                .map(|route| (*flow, route.to_owned()))
+        })
+        .collect();
+
+    UrlBuilder::new(
+        &settings.public_url,
+        routes,
+        settings.url_signing_key.as_bytes(),
+        settings.link_ttl,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder(ttl: i64) -> UrlBuilder {
+        let mut routes = HashMap::new();
+        routes.insert(Flow::ResetPassword, "/{locale}/password/reset".to_owned());
+        UrlBuilder::new("https://skeleton.test/", routes, b"secret", ttl)
+    }
+
+    fn query(url: &str) -> HashMap<String, String> {
+        serde_urlencoded::from_str(url.split('?').nth(1).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn links_are_signed() {
+        let urls = builder(60);
+        let url = urls
+            .build(
+                Flow::ResetPassword,
+                &[("locale", "fr")],
+                &[("user", "42"), ("token", "abc")],
+            )
+            .unwrap();
+        assert!(
+            url.starts_with("https://skeleton.test/fr/password/reset?user=42&token=abc&expires=")
+        );
+
+        let q = query(&url);
+        let expires = q["expires"].parse().unwrap();
+        let params = [("user", "42"), ("token", "abc")];
+        assert!(urls
+            .verify(Flow::ResetPassword, &params, expires, &q["signature"])
+            .is_ok());
+
+        // altered parameters, expiry or flow are rejected
+        let altered = [("user", "43"), ("token", "abc")];
+        assert!(urls
+            .verify(Flow::ResetPassword, &altered, expires, &q["signature"])
+            .is_err());
+        assert!(urls
+            .verify(Flow::ResetPassword, &params, expires + 1, &q["signature"])
+            .is_err());
+    }
+
+    #[test]
+    fn placeholders_are_percent_encoded() {
+        let url = builder(60)
+            .build(Flow::ResetPassword, &[("locale", "../fr?x=1#é")], &[])
+            .unwrap();
+        assert!(url
+            .starts_with("https://skeleton.test/..%2Ffr%3Fx%3D1%23%C3%A9/password/reset?expires="));
+    }
+
+    #[test]
+    fn expired_links_are_rejected() {
+        let urls = builder(-1);
+        let url = urls
+            .build(Flow::ResetPassword, &[("locale", "fr")], &[("user", "42")])
+            .unwrap();
+        assert!(url.starts_with("https://skeleton.test/fr/password/reset?user=42"));
+
+        let q = query(&url);
+        assert!(urls
+            .verify(
+                Flow::ResetPassword,
+                &[("user", "42")],
+                q["expires"].parse().unwrap(),
+                &q["signature"]
+            )
+            .is_err());
+    }
+}